@@ -1,5 +1,5 @@
 use criterion::{BenchmarkId, Criterion, black_box, criterion_group, criterion_main};
-use librbf::{Jit, Program, optimize, parse};
+use librbf::{Jit, Program, Tiered, optimize, parse};
 
 const PARSE_KERNEL: &str = "++>---<[->+<]>[<+>-]>>+[<<+>>-]<[>+[+]<-]";
 const EXECUTE_KERNEL: &str = "-[>+[+]<-]";
@@ -35,22 +35,43 @@ fn bench_compile(c: &mut Criterion) {
 
     for (name, program) in [("medium", &medium), ("large", &large)] {
         group.bench_with_input(BenchmarkId::from_parameter(name), program, |b, program| {
-            b.iter(|| black_box(Jit::new().compile(black_box(program))));
+            b.iter(|| black_box(Jit::new().compile(black_box(program)).unwrap()));
         });
     }
 
     group.finish();
 }
 
+fn bench_tiered(c: &mut Criterion) {
+    let source = repeated_source(PARSE_KERNEL, 8_192);
+    let program = parse_program(source.as_bytes());
+    let mut group = c.benchmark_group("compile_and_run");
+
+    group.bench_function("jit/large", |b| {
+        b.iter(|| Jit::new().compile(black_box(&program)).unwrap().run());
+    });
+    group.bench_function("tiered/large", |b| {
+        b.iter(|| Tiered::new().run(black_box(&program)));
+    });
+
+    group.finish();
+}
+
 fn bench_execute(c: &mut Criterion) {
     let source = repeated_source(EXECUTE_KERNEL, 64);
     let program = parse_program(source.as_bytes());
-    let function = Jit::new().compile(&program);
+    let function = Jit::new().compile(&program).unwrap();
 
     c.bench_function("execute/wrapping_loop", |b| {
         b.iter(|| function.run());
     });
 }
 
-criterion_group!(benches, bench_parse, bench_compile, bench_execute);
+criterion_group!(
+    benches,
+    bench_parse,
+    bench_compile,
+    bench_tiered,
+    bench_execute
+);
 criterion_main!(benches);
@@ -2,7 +2,17 @@
 pub type Program = Vec<Instruction>;
 
 /// An enum representing all brainfuck instructions.
+///
+/// With the `serde` feature, instructions serialize to an object with the instruction name in
+/// snake case under `op` and its operands, if any, under `args`, e.g. `{"op":"add","args":3}`,
+/// `{"op":"mul","args":[1,2]}` or `{"op":"read"}`. Bodies of loops and procedures are arrays
+/// of instructions, bodies of balanced loops arrays of `[offset, instruction]` pairs.
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(tag = "op", content = "args", rename_all = "snake_case")
+)]
 pub enum Instruction {
     /// Adds to the current data cell.
     Add(i64),
@@ -27,4 +37,69 @@ pub enum Instruction {
     WriteBytes(Vec<u8>),
     /// Repeats the `Loop` body until the current data cell is `0`.
     Loop(Program),
+    /// A [`Loop`](Instruction::Loop) whose body leaves the data pointer where it was. Each
+    /// instruction of the body works on the cell at its offset from the loop's cell, so the
+    /// body never moves the data pointer and can't contain instructions that do, like
+    /// `Move`, `Scan`, `Loop` or `Call`.
+    BalancedLoop(Vec<(i64, Instruction)>),
+    /// Defines the procedure numbered by the current data cell's value. The body only runs
+    /// when the procedure is called.
+    Procedure(Program),
+    /// Calls the procedure numbered by the current data cell's value. Calling a procedure that
+    /// was never defined does nothing.
+    Call,
+    /// Ends the program.
+    End,
+    /// Copies the current data cell into the storage register.
+    Store,
+    /// Copies the storage register into the current data cell.
+    Load,
+    /// Shifts the current data cell left by the given number of bits, or right for negative
+    /// amounts. Bits shifted out are lost.
+    Shift(i64),
+    /// Inverts all bits of the current data cell.
+    Not,
+    /// Sets the current data cell to its bitwise AND with the storage register.
+    And,
+    /// Sets the current data cell to its bitwise OR with the storage register.
+    Or,
+    /// Sets the current data cell to its bitwise XOR with the storage register.
+    Xor,
+}
+
+impl Instruction {
+    /// Whether the instruction leaves the data pointer where it was and continues with the
+    /// next instruction, which makes it valid in a [`BalancedLoop`](Instruction::BalancedLoop).
+    pub fn stays_in_place(&self) -> bool {
+        use Instruction::*;
+
+        !matches!(
+            self,
+            Move(_) | Scan(_) | Loop(_) | Procedure(_) | Call | End
+        )
+    }
+}
+
+/// A byte range in Brainfuck source code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Span {
+    /// Offset of the first byte.
+    pub start: usize,
+    /// Offset one past the last byte.
+    pub end: usize,
+}
+
+/// Source locations of a [`Program`], mirroring its structure.
+pub type SourceMap = Vec<SourceNode>;
+
+/// The source location of a single [`Instruction`].
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SourceNode {
+    /// The bytes the instruction was parsed from. Loops and procedures include both brackets.
+    pub span: Span,
+    /// Locations of the body for [`Loop`](Instruction::Loop) and
+    /// [`Procedure`](Instruction::Procedure), empty otherwise.
+    pub body: SourceMap,
 }
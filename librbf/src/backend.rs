@@ -0,0 +1,163 @@
+use crate::ast::Program;
+use crate::jit::{CompileError, Function, Jit};
+use crate::runtime::{Flush, IoMode, OutputFormat};
+use crate::tiered::Tiered;
+
+/// How a compiled program runs, independent of the backend that compiles it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExecutionOptions {
+    /// The number of cells on the tape.
+    pub tape_size: usize,
+    /// Whether all cells are `0` when the program starts, see [`Jit::assume_zeroed_tape`].
+    pub zeroed_tape: bool,
+    /// What `,` and `.` read and write.
+    pub io_mode: IoMode,
+    /// When output is written to stdout.
+    pub flush: Flush,
+    /// How written bytes are shown.
+    pub output_format: OutputFormat,
+}
+
+/// What a [`Backend`] turns a program into.
+pub enum Artifact {
+    /// Code that runs on a tape.
+    Runnable(Box<dyn Runnable>),
+    /// Text or bytes that stand for the program, e.g. source code in another language.
+    Emitted(Vec<u8>),
+}
+
+/// A compiled program that can run on a caller-supplied tape.
+pub trait Runnable {
+    /// Runs the program on `tape` with the data pointer starting at cell `start`, and returns
+    /// the cell the data pointer ended on.
+    ///
+    /// Panics if `start` is outside the tape or the data pointer ends outside of it.
+    fn run_on(&self, tape: &mut [u8], start: usize) -> usize;
+}
+
+/// Compiles programs into something that runs them or represents them.
+///
+/// [`backends`] lists the backends available on the host, and [`find_backend`] looks one up
+/// by name.
+pub trait Backend {
+    /// The name the backend is selected by, e.g. `x86_64-jit`.
+    fn name(&self) -> &'static str;
+
+    /// A short description of what the backend produces.
+    fn description(&self) -> &'static str;
+
+    /// Compiles `program` to run with `options`.
+    fn compile(
+        &self,
+        program: &Program,
+        options: &ExecutionOptions,
+    ) -> Result<Artifact, CompileError>;
+}
+
+/// The JIT for the host's architecture, which compiles whole programs to native code.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JitBackend;
+
+/// A program on the [`Tiered`] interpreter, with the options it runs with.
+struct TieredProgram {
+    tiered: Tiered,
+    program: Program,
+}
+
+/// Returns every backend available on the host, starting with the default one.
+pub fn backends() -> Vec<Box<dyn Backend>> {
+    vec![Box::new(JitBackend), Box::new(Tiered::new())]
+}
+
+/// Returns the backend called `name` if it is available on the host.
+pub fn find_backend(name: &str) -> Option<Box<dyn Backend>> {
+    backends()
+        .into_iter()
+        .find(|backend| backend.name() == name)
+}
+
+impl Default for ExecutionOptions {
+    fn default() -> Self {
+        ExecutionOptions {
+            tape_size: 30_000,
+            zeroed_tape: true,
+            io_mode: IoMode::default(),
+            flush: Flush::default(),
+            output_format: OutputFormat::default(),
+        }
+    }
+}
+
+impl Backend for JitBackend {
+    #[cfg(target_arch = "x86_64")]
+    fn name(&self) -> &'static str {
+        "x86_64-jit"
+    }
+
+    #[cfg(target_arch = "aarch64")]
+    fn name(&self) -> &'static str {
+        "aarch64-jit"
+    }
+
+    fn description(&self) -> &'static str {
+        "Compiles the whole program to native code before it runs"
+    }
+
+    fn compile(
+        &self,
+        program: &Program,
+        options: &ExecutionOptions,
+    ) -> Result<Artifact, CompileError> {
+        let function = Jit::new()
+            .set_tape_size(options.tape_size)
+            .assume_zeroed_tape(options.zeroed_tape)
+            .set_io_mode(options.io_mode)
+            .compile(program)?
+            .set_flush(options.flush)
+            .set_output_format(options.output_format);
+
+        Ok(Artifact::Runnable(Box::new(function)))
+    }
+}
+
+impl Backend for Tiered {
+    fn name(&self) -> &'static str {
+        "tiered"
+    }
+
+    fn description(&self) -> &'static str {
+        "Interprets the program and compiles loops that run often"
+    }
+
+    fn compile(
+        &self,
+        program: &Program,
+        options: &ExecutionOptions,
+    ) -> Result<Artifact, CompileError> {
+        let tiered = self
+            .clone()
+            .set_tape_size(options.tape_size)
+            .set_io_mode(options.io_mode)
+            .set_flush(options.flush)
+            .set_output_format(options.output_format);
+
+        Ok(Artifact::Runnable(Box::new(TieredProgram {
+            tiered,
+            program: program.clone(),
+        })))
+    }
+}
+
+impl Runnable for Function {
+    fn run_on(&self, tape: &mut [u8], start: usize) -> usize {
+        Function::run_on(self, tape, start)
+    }
+}
+
+impl Runnable for TieredProgram {
+    fn run_on(&self, tape: &mut [u8], start: usize) -> usize {
+        let mut context = self.tiered.context();
+        let (ptr, _) = self.tiered.run_on(&self.program, tape, start, &mut context);
+        ptr.expect("data pointer moved outside the tape")
+    }
+}
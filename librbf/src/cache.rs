@@ -0,0 +1,197 @@
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::jit::Function;
+use crate::jit::common::{Helper, Relocation, Target};
+use crate::opt;
+
+/// Starts every cache file, followed by the version of the file format.
+const MAGIC: &[u8; 8] = b"RBFCACHE";
+const FORMAT_VERSION: u64 = 3;
+
+/// Identifies the code compiled from a program with a given set of options.
+///
+/// The key holds the source, the versions of `librbf` and its optimizer, the target
+/// architecture and every option added with [`option`](CacheKey::option). Only its file name
+/// is a hash, so sources that share a file name never load each other's code.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheKey {
+    material: Vec<u8>,
+}
+
+/// A directory of compiled code, keyed by [`CacheKey`].
+///
+/// Code is stored together with its byte literals and the locations of all absolute addresses
+/// in it, which are patched when the code is loaded into another process.
+#[derive(Clone, Debug)]
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl CacheKey {
+    /// Creates a key for code compiled from `source` by this version of `librbf`.
+    ///
+    /// [`Jit::cache_key`](crate::Jit::cache_key) also adds the version of the backend's code
+    /// generation and the backend options.
+    pub fn new(source: &[u8]) -> CacheKey {
+        let mut key = CacheKey {
+            material: Vec::new(),
+        }
+        .option("librbf", env!("CARGO_PKG_VERSION"))
+        .option("optimizer", opt::VERSION)
+        .option("arch", std::env::consts::ARCH)
+        .option("source-len", source.len());
+
+        // The length comes first, so no source can pass for another followed by options.
+        key.material.extend_from_slice(source);
+        key.material.push(b'\n');
+        key
+    }
+
+    /// Adds an option that changes the generated code, like the enabled extensions.
+    pub fn option(mut self, name: &str, value: impl Display) -> Self {
+        writeln!(self.material, "{}={}", name, value).unwrap();
+        self
+    }
+
+    fn file_name(&self) -> String {
+        format!("{:032x}.bin", fnv1a(&self.material))
+    }
+}
+
+impl Cache {
+    /// Uses `dir` as the cache directory. It is created when the first entry is stored.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Cache {
+        Cache { dir: dir.into() }
+    }
+
+    /// Loads the code stored for `key`, or returns `None` if there is none.
+    ///
+    /// Fails if the entry can't be read or is corrupt.
+    pub fn load(&self, key: &CacheKey) -> io::Result<Option<Function>> {
+        let bytes = match fs::read(self.dir.join(key.file_name())) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut reader = Reader { bytes: &bytes };
+
+        if reader.take(MAGIC.len())? != MAGIC || reader.u64()? != FORMAT_VERSION {
+            return Err(invalid("not a cache file of this version"));
+        }
+
+        // Different keys can share a file name, so the whole key is compared.
+        if reader.bytes()? != key.material {
+            return Ok(None);
+        }
+
+        let tape_size = reader.u64()? as usize;
+        let start = reader.u64()? as usize;
+        let reach = reader.u64()?;
+        let code = reader.bytes()?;
+
+        let literals = (0..reader.u64()?)
+            .map(|_| Ok(reader.bytes()?.into()))
+            .collect::<io::Result<_>>()?;
+
+        let relocations = (0..reader.u64()?)
+            .map(|_| {
+                let offset = reader.u64()? as usize;
+                let target = match (reader.u64()?, reader.u64()? as usize) {
+                    (0, index) if index < Helper::ALL.len() => Target::Helper(Helper::ALL[index]),
+                    (1, index) => Target::Literal(index),
+                    _ => return Err(invalid("unknown relocation target")),
+                };
+                Ok(Relocation { offset, target })
+            })
+            .collect::<io::Result<_>>()?;
+
+        Function::load(code, start, tape_size, reach, literals, relocations).map(Some)
+    }
+
+    /// Stores `function` as the code for `key`, replacing any previous entry.
+    pub fn store(&self, key: &CacheKey, function: &Function) -> io::Result<()> {
+        let mut out = Vec::new();
+
+        out.extend_from_slice(MAGIC);
+        write_u64(&mut out, FORMAT_VERSION);
+        write_bytes(&mut out, &key.material);
+        write_u64(&mut out, function.tape_size() as u64);
+        write_u64(&mut out, function.start() as u64);
+        write_u64(&mut out, function.reach());
+        write_bytes(&mut out, function.code());
+
+        write_u64(&mut out, function.literals().len() as u64);
+        for literal in function.literals() {
+            write_bytes(&mut out, literal);
+        }
+
+        write_u64(&mut out, function.relocations().len() as u64);
+        for relocation in function.relocations() {
+            let (kind, index) = match relocation.target {
+                Target::Helper(helper) => (0, Helper::ALL.iter().position(|&h| h == helper)),
+                Target::Literal(index) => (1, Some(index)),
+            };
+            write_u64(&mut out, relocation.offset as u64);
+            write_u64(&mut out, kind);
+            write_u64(&mut out, index.unwrap() as u64);
+        }
+
+        // Write to a temporary file first so concurrent runs never see a partial entry.
+        fs::create_dir_all(&self.dir)?;
+        let path = self.dir.join(key.file_name());
+        let tmp = path.with_extension(format!("tmp{}", std::process::id()));
+        fs::write(&tmp, out)?;
+        fs::rename(tmp, path)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        if len > self.bytes.len() {
+            return Err(invalid("truncated cache file"));
+        }
+
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> io::Result<&'a [u8]> {
+        let len = self.u64()?;
+        self.take(len.try_into().unwrap_or(usize::MAX))
+    }
+}
+
+fn write_u64(out: &mut Vec<u8>, value: u64) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u64(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// 128-bit FNV-1a.
+fn fnv1a(bytes: &[u8]) -> u128 {
+    const OFFSET: u128 = 0x6c62272e07bb014262b821756295c58d;
+    const PRIME: u128 = 0x0000000001000000000000000000013b;
+
+    bytes
+        .iter()
+        .fold(OFFSET, |hash, &b| (hash ^ b as u128).wrapping_mul(PRIME))
+}
@@ -0,0 +1,298 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::ops::ControlFlow;
+
+use crate::ast::{Instruction::*, *};
+use crate::interpreter::{Hooks, Interpreter, Iteration, Stop};
+use crate::runtime::{Context, Flush, OutputFormat};
+
+/// Execution counts for one instruction of the source program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    /// The source bytes the instruction was parsed from.
+    pub span: Span,
+    /// How often the instruction was executed. For loops this counts how often the loop was
+    /// reached, which is also how often it was left.
+    pub hits: u64,
+    /// How often the body of a loop was executed, `None` for other instructions.
+    pub iterations: Option<u64>,
+}
+
+/// Coverage of a Brainfuck program, collected by running it on an instrumented interpreter.
+///
+/// The program has to be unoptimized so every instruction still corresponds to the
+/// [`SourceNode`] it was parsed from.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Coverage {
+    regions: Vec<Region>,
+}
+
+/// Counts executions for the interpreter.
+struct Collector {
+    context: Context,
+    regions: Vec<Region>,
+    // The region of every instruction, by address.
+    instructions: HashMap<*const Instruction, usize>,
+}
+
+impl Coverage {
+    /// Runs `program` with a tape of `tape_size` cells and records how often each instruction
+    /// was executed. Input and output go to STDIN and STDOUT like a compiled program's.
+    ///
+    /// Panics if the data pointer leaves the tape, or when reaching a
+    /// [`BalancedLoop`](Instruction::BalancedLoop), which only optimized programs contain.
+    pub fn collect(program: &Program, map: &SourceMap, tape_size: usize) -> Coverage {
+        let mut collector = Collector {
+            context: Context::new(Flush::default(), OutputFormat::default()),
+            regions: Vec::new(),
+            instructions: HashMap::new(),
+        };
+        collector.add_regions(program, map);
+
+        let mut tape = vec![0; tape_size];
+        let mut interpreter = Interpreter::new(&mut tape[..], 0, collector);
+
+        if interpreter.execute(program) == ControlFlow::Break(Stop::LeftTape) {
+            panic!("data pointer moved outside the tape");
+        }
+
+        Coverage {
+            regions: interpreter.hooks.regions,
+        }
+    }
+
+    /// The instruction counts in source order. Loops come before their bodies.
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Writes the coverage as an lcov tracefile for the source file at `path`.
+    ///
+    /// Each line containing the start of an instruction gets a `DA` record with the highest
+    /// count on that line. Every loop contributes one `BRDA` branch for entering its body
+    /// and one for leaving it.
+    pub fn write_lcov<W: Write>(&self, out: &mut W, path: &str, source: &[u8]) -> io::Result<()> {
+        let lines = LineIndex::new(source);
+
+        writeln!(out, "TN:")?;
+        writeln!(out, "SF:{}", path)?;
+
+        let mut branches = 0;
+        let mut branches_hit = 0;
+
+        for (block, region) in self
+            .regions
+            .iter()
+            .filter(|region| region.iterations.is_some())
+            .enumerate()
+        {
+            let line = lines.line(region.span.start);
+            let outcomes = [region.iterations.unwrap_or(0), region.hits];
+
+            for (branch, &taken) in outcomes.iter().enumerate() {
+                branches += 1;
+
+                if region.hits == 0 {
+                    writeln!(out, "BRDA:{},{},{},-", line, block, branch)?;
+                } else {
+                    writeln!(out, "BRDA:{},{},{},{}", line, block, branch, taken)?;
+                    branches_hit += (taken > 0) as usize;
+                }
+            }
+        }
+
+        writeln!(out, "BRF:{}", branches)?;
+        writeln!(out, "BRH:{}", branches_hit)?;
+
+        let counts = self.line_counts(&lines);
+
+        for (line, hits) in counts.iter().enumerate() {
+            if let Some(hits) = hits {
+                writeln!(out, "DA:{},{}", line + 1, hits)?;
+            }
+        }
+
+        writeln!(out, "LF:{}", counts.iter().flatten().count())?;
+        writeln!(
+            out,
+            "LH:{}",
+            counts.iter().flatten().filter(|&&n| n > 0).count()
+        )?;
+        writeln!(out, "end_of_record")
+    }
+
+    /// Writes the source annotated with execution counts, in the style of `gcov`.
+    ///
+    /// Lines without instructions are marked with `-` and lines that were never executed with
+    /// `#####`. Below the other lines, `^` points at the instructions (or loop brackets) that
+    /// never ran.
+    pub fn write_listing<W: Write>(&self, out: &mut W, source: &[u8]) -> io::Result<()> {
+        let lines = LineIndex::new(source);
+        let counts = self.line_counts(&lines);
+        let mut missed = vec![Vec::new(); lines.len()];
+
+        for region in self.regions.iter().filter(|region| region.hits == 0) {
+            missed[lines.line(region.span.start) - 1].push(region.span.start);
+
+            // The closing bracket may be on a later line than the loop's start.
+            if region.iterations.is_some() {
+                let end = region.span.end - 1;
+                missed[lines.line(end) - 1].push(end);
+            }
+        }
+
+        for (index, text) in lines.texts(source).enumerate() {
+            let count = match counts[index] {
+                None => "-".to_string(),
+                Some(0) => "#####".to_string(),
+                Some(n) => n.to_string(),
+            };
+
+            write!(out, "{:>9}:{:>5}:", count, index + 1)?;
+            out.write_all(text)?;
+            writeln!(out)?;
+
+            if counts[index] == Some(0) || missed[index].is_empty() {
+                continue;
+            }
+
+            let start = lines.starts[index];
+            let mut marker = Vec::new();
+
+            for (column, &c) in text.iter().enumerate() {
+                if missed[index].contains(&(start + column)) {
+                    marker.push(b'^');
+                } else if c == b'\t' {
+                    marker.push(b'\t');
+                } else {
+                    marker.push(b' ');
+                }
+            }
+
+            while marker.last() != Some(&b'^') {
+                marker.pop();
+            }
+
+            write!(out, "{:>9} {:>5} ", "", "")?;
+            out.write_all(&marker)?;
+            writeln!(out)?;
+        }
+
+        Ok(())
+    }
+
+    fn line_counts(&self, lines: &LineIndex) -> Vec<Option<u64>> {
+        let mut counts = vec![None; lines.len()];
+
+        for region in self.regions.iter() {
+            let count = &mut counts[lines.line(region.span.start) - 1];
+            *count = Some(count.unwrap_or(0).max(region.hits));
+        }
+
+        counts
+    }
+}
+
+impl Collector {
+    fn add_regions(&mut self, program: &Program, map: &SourceMap) {
+        assert_eq!(
+            program.len(),
+            map.len(),
+            "source map does not match the program"
+        );
+
+        for (ins, node) in program.iter().zip(map.iter()) {
+            self.instructions.insert(ins, self.regions.len());
+            self.regions.push(Region {
+                span: node.span,
+                hits: 0,
+                iterations: matches!(ins, Loop(_)).then_some(0),
+            });
+
+            if let Loop(body) | Procedure(body) = ins {
+                self.add_regions(body, &node.body);
+            }
+        }
+    }
+
+    fn region(&mut self, ins: &Instruction) -> &mut Region {
+        &mut self.regions[self.instructions[&(ins as *const _)]]
+    }
+}
+
+impl<'p> Hooks<'p, &mut [u8]> for Collector {
+    fn instruction(&mut self, ins: &'p Instruction) -> ControlFlow<()> {
+        if let BalancedLoop(_) = ins {
+            panic!("coverage needs an unoptimized program");
+        }
+
+        self.region(ins).hits += 1;
+        ControlFlow::Continue(())
+    }
+
+    fn iteration(
+        &mut self,
+        ins: &'p Instruction,
+        _tape: &mut &mut [u8],
+        _ptr: &mut usize,
+    ) -> ControlFlow<(), Iteration> {
+        if let Some(iterations) = self.region(ins).iterations.as_mut() {
+            *iterations += 1;
+        }
+
+        ControlFlow::Continue(Iteration::Interpret)
+    }
+
+    fn read(&mut self) -> u8 {
+        self.context.read()
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.context.write(bytes);
+    }
+}
+
+struct LineIndex {
+    starts: Vec<usize>,
+    len: usize,
+}
+
+impl LineIndex {
+    fn new(source: &[u8]) -> Self {
+        let mut starts = vec![0];
+        starts.extend(
+            source
+                .iter()
+                .enumerate()
+                .filter(|&(_, &c)| c == b'\n')
+                .map(|(i, _)| i + 1),
+        );
+
+        if starts.len() > 1 && *starts.last().unwrap() == source.len() {
+            starts.pop();
+        }
+
+        Self {
+            starts,
+            len: source.len(),
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.starts.len()
+    }
+
+    /// The 1-based line containing the byte at `offset`.
+    fn line(&self, offset: usize) -> usize {
+        self.starts.partition_point(|&start| start <= offset)
+    }
+
+    fn texts<'a>(&'a self, source: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+        self.starts.iter().enumerate().map(move |(i, &start)| {
+            let end = self.starts.get(i + 1).map_or(self.len, |&next| next - 1);
+            let text = &source[start..end.max(start)];
+            let text = text.strip_suffix(b"\n").unwrap_or(text);
+            text.strip_suffix(b"\r").unwrap_or(text)
+        })
+    }
+}
@@ -0,0 +1,259 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::ast::{Program, SourceMap, Span};
+use crate::parser::parse_with_spans;
+
+/// The Brainfuck commands a dialect token can stand for.
+const COMMANDS: &[u8] = b"+-<>,.[]";
+
+/// A token-substitution dialect of Brainfuck, such as Ook! or alphuck.
+///
+/// A dialect maps token sequences to the eight Brainfuck commands. Whitespace inside a token
+/// matches any non-empty run of whitespace in the source, and bytes that don't start a token are
+/// treated as comments. Programs are translated to plain Brainfuck and parsed into the same
+/// [`Program`] as regular source.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dialect {
+    // Sorted by length, longest first, so longer tokens win over their prefixes.
+    tokens: Vec<(Vec<Vec<u8>>, u8)>,
+}
+
+/// An error in a dialect definition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DialectError {
+    /// A mapping line names something other than one of the eight commands.
+    UnknownCommand { line: usize, command: String },
+    /// A mapping line has no token after the command.
+    MissingToken { line: usize },
+}
+
+impl Dialect {
+    /// Creates a dialect from `(command, token)` pairs. A command may have several tokens.
+    pub fn new<'a, I>(tokens: I) -> Result<Self, DialectError>
+    where
+        I: IntoIterator<Item = (u8, &'a str)>,
+    {
+        let mut dialect = Dialect { tokens: Vec::new() };
+
+        for (line, (command, token)) in tokens.into_iter().enumerate() {
+            dialect.insert(line + 1, command, token)?;
+        }
+
+        Ok(dialect)
+    }
+
+    /// Reads a dialect from a mapping file.
+    ///
+    /// Each line holds one of the eight commands followed by whitespace and the token that
+    /// stands for it. Empty lines and lines starting with `#` are ignored:
+    ///
+    /// ```text
+    /// # Ook!
+    /// > Ook. Ook?
+    /// < Ook? Ook.
+    /// ```
+    pub fn from_mapping(mapping: &str) -> Result<Self, DialectError> {
+        let mut dialect = Dialect { tokens: Vec::new() };
+
+        for (index, line) in mapping.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (command, token) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+            let &[command] = command.as_bytes() else {
+                return Err(DialectError::UnknownCommand {
+                    line: line_number,
+                    command: command.to_string(),
+                });
+            };
+
+            dialect.insert(line_number, command, token)?;
+        }
+
+        Ok(dialect)
+    }
+
+    /// Looks up a built-in dialect: `brainfuck`, `ook`, `blub` or `alphuck`.
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "brainfuck" | "bf" => Some(Self::brainfuck()),
+            "ook" => Some(Self::ook()),
+            "blub" => Some(Self::blub()),
+            "alphuck" => Some(Self::alphuck()),
+            _ => None,
+        }
+    }
+
+    /// Plain Brainfuck, where every command is its own token.
+    pub fn brainfuck() -> Self {
+        Self::with_words(["+", "-", "<", ">", ",", ".", "[", "]"])
+    }
+
+    /// [Ook!](https://esolangs.org/wiki/Ook!), built from pairs of `Ook.`, `Ook?` and `Ook!`.
+    pub fn ook() -> Self {
+        Self::with_pairs("Ook")
+    }
+
+    /// [Blub](https://esolangs.org/wiki/Blub), Ook! with `Blub` instead of `Ook`.
+    pub fn blub() -> Self {
+        Self::with_pairs("Blub")
+    }
+
+    /// [alphuck](https://esolangs.org/wiki/Alphuck), which uses the letters `eicajops`.
+    pub fn alphuck() -> Self {
+        Self::with_words(["e", "i", "c", "a", "o", "j", "p", "s"])
+    }
+
+    fn with_pairs(word: &str) -> Self {
+        let pair = |a, b| format!("{word}{a} {word}{b}");
+
+        Self::with_words([
+            pair('.', '.'),
+            pair('!', '!'),
+            pair('?', '.'),
+            pair('.', '?'),
+            pair('.', '!'),
+            pair('!', '.'),
+            pair('!', '?'),
+            pair('?', '!'),
+        ])
+    }
+
+    /// Builds a dialect from one token per command, in the order of `+-<>,.[]`.
+    fn with_words<S: AsRef<str>>(words: [S; 8]) -> Self {
+        let tokens = COMMANDS
+            .iter()
+            .copied()
+            .zip(words.iter().map(AsRef::as_ref));
+        Self::new(tokens).expect("built-in dialects are valid")
+    }
+
+    fn insert(&mut self, line: usize, command: u8, token: &str) -> Result<(), DialectError> {
+        if !COMMANDS.contains(&command) {
+            return Err(DialectError::UnknownCommand {
+                line,
+                command: (command as char).to_string(),
+            });
+        }
+
+        let words: Vec<Vec<u8>> = token
+            .split_whitespace()
+            .map(|word| word.as_bytes().to_vec())
+            .collect();
+
+        if words.is_empty() {
+            return Err(DialectError::MissingToken { line });
+        }
+
+        let len = |words: &[Vec<u8>]| words.iter().map(Vec::len).sum::<usize>();
+        let index = self
+            .tokens
+            .partition_point(|(existing, _)| len(existing) >= len(&words));
+        self.tokens.insert(index, (words, command));
+
+        Ok(())
+    }
+
+    /// Translates dialect source into plain Brainfuck.
+    pub fn translate(&self, source: &[u8]) -> Vec<u8> {
+        self.translate_with_spans(source).0
+    }
+
+    /// Translates dialect source into plain Brainfuck, also returning the span of the token each
+    /// command was translated from.
+    pub fn translate_with_spans(&self, source: &[u8]) -> (Vec<u8>, Vec<Span>) {
+        let mut commands = Vec::new();
+        let mut spans = Vec::new();
+        let mut start = 0;
+
+        while start < source.len() {
+            let token = self
+                .tokens
+                .iter()
+                .find_map(|(words, command)| Some((match_words(source, start, words)?, *command)));
+
+            if let Some((end, command)) = token {
+                commands.push(command);
+                spans.push(Span { start, end });
+                start = end;
+            } else {
+                start += 1;
+            }
+        }
+
+        (commands, spans)
+    }
+
+    /// Parses dialect source and returns a [Program](type.Program.html).
+    pub fn parse(&self, source: &[u8]) -> Program {
+        self.parse_with_spans(source).0
+    }
+
+    /// Parses dialect source and returns a [Program](type.Program.html) together with a
+    /// [SourceMap](type.SourceMap.html) pointing into the original dialect source.
+    pub fn parse_with_spans(&self, source: &[u8]) -> (Program, SourceMap) {
+        let (commands, spans) = self.translate_with_spans(source);
+        let (program, mut map) = parse_with_spans(&commands[..]);
+
+        remap_spans(&mut map, &spans);
+
+        (program, map)
+    }
+}
+
+impl fmt::Display for DialectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DialectError::UnknownCommand { line, command } => {
+                write!(f, "line {}: `{}` is not a Brainfuck command", line, command)
+            }
+            DialectError::MissingToken { line } => write!(f, "line {}: missing token", line),
+        }
+    }
+}
+
+impl Error for DialectError {}
+
+/// Matches the words of a token at `start`, returning the end of the match.
+fn match_words(source: &[u8], start: usize, words: &[Vec<u8>]) -> Option<usize> {
+    let mut end = start;
+
+    for (i, word) in words.iter().enumerate() {
+        if i > 0 {
+            let spaces = source[end..]
+                .iter()
+                .take_while(|c| c.is_ascii_whitespace())
+                .count();
+
+            if spaces == 0 {
+                return None;
+            }
+
+            end += spaces;
+        }
+
+        if !source[end..].starts_with(word) {
+            return None;
+        }
+
+        end += word.len();
+    }
+
+    Some(end)
+}
+
+/// Rewrites spans over translated commands into spans over the tokens they came from.
+fn remap_spans(map: &mut SourceMap, spans: &[Span]) {
+    for node in map.iter_mut() {
+        node.span = Span {
+            start: spans[node.span.start].start,
+            end: spans[node.span.end - 1].end,
+        };
+        remap_spans(&mut node.body, spans);
+    }
+}
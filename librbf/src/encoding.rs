@@ -0,0 +1,290 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::ast::{Instruction, Instruction::*, Program};
+
+/// Version of the binary encoding, stored in its first byte.
+const VERSION: u8 = 1;
+
+/// How deeply loops and procedures may nest in decoded programs, which keeps untrusted input
+/// from overflowing the stack.
+const MAX_DEPTH: usize = 1_000;
+
+/// An error while decoding a binary-encoded [`Program`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input was encoded with a different version of the format.
+    UnsupportedVersion(u8),
+    /// The input ends in the middle of an instruction.
+    UnexpectedEnd,
+    /// An instruction has an unknown tag.
+    UnknownTag(u8),
+    /// A number doesn't fit into 64 bits.
+    Overflow,
+    /// A `WriteBytes` has no bytes to write.
+    EmptyWrite,
+    /// Loops and procedures are nested too deeply.
+    TooDeep,
+    /// The input continues after the program.
+    TrailingBytes,
+}
+
+/// Encodes `program` in a compact binary format.
+///
+/// The first byte is the format version. It is followed by the number of instructions and the
+/// instructions themselves, each a one-byte tag followed by its operands. Numbers are LEB128
+/// varints, signed ones zigzag-encoded first, and loop and procedure bodies are nested
+/// programs without the version byte. Balanced loop bodies are encoded like programs with
+/// each instruction preceded by its offset.
+pub fn encode(program: &Program) -> Vec<u8> {
+    let mut out = vec![VERSION];
+    encode_program(&mut out, program);
+    out
+}
+
+/// Decodes a program produced by [`encode`].
+pub fn decode(bytes: &[u8]) -> Result<Program, DecodeError> {
+    let mut decoder = Decoder { bytes, depth: 0 };
+
+    match decoder.byte()? {
+        VERSION => (),
+        version => return Err(DecodeError::UnsupportedVersion(version)),
+    }
+
+    let program = decoder.program()?;
+
+    if !decoder.bytes.is_empty() {
+        return Err(DecodeError::TrailingBytes);
+    }
+
+    Ok(program)
+}
+
+fn encode_program(out: &mut Vec<u8>, program: &Program) {
+    write_unsigned(out, program.len() as u64);
+
+    for ins in program {
+        encode_instruction(out, ins);
+    }
+}
+
+fn encode_instruction(out: &mut Vec<u8>, ins: &Instruction) {
+    out.push(tag(ins));
+
+    match *ins {
+        Add(n) | Move(n) | Set(n) | Scan(n) | WriteConst(n) | Shift(n) => write_signed(out, n),
+        Mul(offset, factor) => {
+            write_signed(out, offset);
+            write_signed(out, factor);
+        }
+        MulRun(ref muls) => {
+            write_unsigned(out, muls.len() as u64);
+
+            for &(offset, factor) in muls {
+                write_signed(out, offset);
+                write_signed(out, factor);
+            }
+        }
+        WriteBytes(ref bytes) => {
+            write_unsigned(out, bytes.len() as u64);
+            out.extend_from_slice(bytes);
+        }
+        Loop(ref body) | Procedure(ref body) => encode_program(out, body),
+        BalancedLoop(ref body) => {
+            write_unsigned(out, body.len() as u64);
+
+            for (offset, ins) in body {
+                write_signed(out, *offset);
+                encode_instruction(out, ins);
+            }
+        }
+        Read | Write | Call | End | Store | Load | Not | And | Or | Xor => (),
+    }
+}
+
+fn tag(ins: &Instruction) -> u8 {
+    match ins {
+        Add(_) => 0,
+        Move(_) => 1,
+        Set(_) => 2,
+        Mul(..) => 3,
+        MulRun(_) => 4,
+        Scan(_) => 5,
+        Read => 6,
+        Write => 7,
+        WriteConst(_) => 8,
+        WriteBytes(_) => 9,
+        Loop(_) => 10,
+        Procedure(_) => 11,
+        Call => 12,
+        End => 13,
+        Store => 14,
+        Load => 15,
+        Shift(_) => 16,
+        Not => 17,
+        And => 18,
+        Or => 19,
+        Xor => 20,
+        BalancedLoop(_) => 21,
+    }
+}
+
+fn write_unsigned(out: &mut Vec<u8>, mut n: u64) {
+    while n >= 0x80 {
+        out.push(n as u8 | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn write_signed(out: &mut Vec<u8>, n: i64) {
+    write_unsigned(out, ((n << 1) ^ (n >> 63)) as u64);
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    depth: usize,
+}
+
+impl Decoder<'_> {
+    fn program(&mut self) -> Result<Program, DecodeError> {
+        let len = self.unsigned()?;
+        // Every instruction takes at least one byte, which bounds the allocation.
+        let mut program = Vec::with_capacity(len.min(self.bytes.len() as u64) as usize);
+
+        for _ in 0..len {
+            program.push(self.instruction()?);
+        }
+
+        Ok(program)
+    }
+
+    fn instruction(&mut self) -> Result<Instruction, DecodeError> {
+        let ins = match self.byte()? {
+            0 => Add(self.signed()?),
+            1 => Move(self.signed()?),
+            2 => Set(self.signed()?),
+            3 => Mul(self.signed()?, self.signed()?),
+            4 => {
+                let len = self.unsigned()?;
+                let mut muls = Vec::new();
+
+                for _ in 0..len {
+                    muls.push((self.signed()?, self.signed()?));
+                }
+
+                MulRun(muls)
+            }
+            5 => Scan(self.signed()?),
+            6 => Read,
+            7 => Write,
+            8 => WriteConst(self.signed()?),
+            9 => {
+                let len = self.unsigned()?;
+
+                if len == 0 {
+                    return Err(DecodeError::EmptyWrite);
+                }
+
+                if len > self.bytes.len() as u64 {
+                    return Err(DecodeError::UnexpectedEnd);
+                }
+
+                let (bytes, rest) = self.bytes.split_at(len as usize);
+                self.bytes = rest;
+                WriteBytes(bytes.to_vec())
+            }
+            10 => Loop(self.nested(Self::program)?),
+            11 => Procedure(self.nested(Self::program)?),
+            12 => Call,
+            13 => End,
+            14 => Store,
+            15 => Load,
+            16 => Shift(self.signed()?),
+            17 => Not,
+            18 => And,
+            19 => Or,
+            20 => Xor,
+            21 => BalancedLoop(self.nested(Self::balanced_body)?),
+            tag => return Err(DecodeError::UnknownTag(tag)),
+        };
+
+        Ok(ins)
+    }
+
+    fn balanced_body(&mut self) -> Result<Vec<(i64, Instruction)>, DecodeError> {
+        let len = self.unsigned()?;
+        let mut body = Vec::with_capacity(len.min(self.bytes.len() as u64) as usize);
+
+        for _ in 0..len {
+            body.push((self.signed()?, self.instruction()?));
+        }
+
+        Ok(body)
+    }
+
+    /// Decodes the body of a loop or procedure with `decode`, one level deeper.
+    fn nested<T>(
+        &mut self,
+        decode: impl FnOnce(&mut Self) -> Result<T, DecodeError>,
+    ) -> Result<T, DecodeError> {
+        if self.depth == MAX_DEPTH {
+            return Err(DecodeError::TooDeep);
+        }
+
+        self.depth += 1;
+        let body = decode(self);
+        self.depth -= 1;
+        body
+    }
+
+    fn byte(&mut self) -> Result<u8, DecodeError> {
+        let (&byte, rest) = self.bytes.split_first().ok_or(DecodeError::UnexpectedEnd)?;
+        self.bytes = rest;
+        Ok(byte)
+    }
+
+    fn unsigned(&mut self) -> Result<u64, DecodeError> {
+        let mut n = 0;
+
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            let bits = (byte & 0x7F) as u64;
+
+            if bits << shift >> shift != bits {
+                return Err(DecodeError::Overflow);
+            }
+
+            n |= bits << shift;
+
+            if byte & 0x80 == 0 {
+                return Ok(n);
+            }
+        }
+
+        Err(DecodeError::Overflow)
+    }
+
+    fn signed(&mut self) -> Result<i64, DecodeError> {
+        let n = self.unsigned()?;
+        Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::UnsupportedVersion(version) => {
+                write!(f, "unsupported encoding version {}", version)
+            }
+            DecodeError::UnexpectedEnd => write!(f, "unexpected end of input"),
+            DecodeError::UnknownTag(tag) => write!(f, "unknown instruction tag {}", tag),
+            DecodeError::Overflow => write!(f, "number too large"),
+            DecodeError::EmptyWrite => write!(f, "write without any bytes"),
+            DecodeError::TooDeep => write!(f, "loops nested too deeply"),
+            DecodeError::TrailingBytes => write!(f, "unexpected bytes after the program"),
+        }
+    }
+}
+
+impl Error for DecodeError {}
@@ -0,0 +1,133 @@
+#[derive(Clone, Copy)]
+enum CellState {
+    /// Zero on a zeroed tape, unknown otherwise.
+    Default,
+    Known(u8),
+    Unknown,
+}
+
+/// Cells tracked at once. Programs that spread further forget everything.
+const MAX_FACT_CELLS: usize = 4096;
+
+/// The cell values known at some point of a program, by offset from a base cell.
+///
+/// This is the dataflow state shared by the optimizer's
+/// [`Pass::ConstProp`](crate::Pass::ConstProp) and the AArch64 backend.
+pub(crate) struct CellFacts {
+    default_zero: bool,
+    base: i64,
+    cells: Vec<CellState>,
+}
+
+impl CellFacts {
+    /// Creates facts for a program that starts on a zeroed tape if `default_zero` is set, or
+    /// on unknown cells otherwise.
+    pub(crate) fn new(default_zero: bool) -> Self {
+        Self {
+            default_zero,
+            base: 0,
+            cells: Vec::new(),
+        }
+    }
+
+    fn index(&self, offset: i64) -> Option<usize> {
+        let index = offset - self.base;
+
+        (index >= 0 && (index as usize) < self.cells.len()).then_some(index as usize)
+    }
+
+    fn state(&self, offset: i64) -> CellState {
+        if let Some(index) = self.index(offset) {
+            self.cells[index]
+        } else {
+            CellState::Default
+        }
+    }
+
+    /// The value of the cell at `offset`, if it is known.
+    pub(crate) fn known(&self, offset: i64) -> Option<u8> {
+        match self.state(offset) {
+            CellState::Known(n) => Some(n),
+            CellState::Default if self.default_zero => Some(0),
+            CellState::Unknown | CellState::Default => None,
+        }
+    }
+
+    pub(crate) fn set_known(&mut self, offset: i64, value: u8) {
+        if self.default_zero && value == 0 {
+            self.set_default(offset);
+            return;
+        }
+        if let Some(i) = self.ensure_offset(offset) {
+            self.cells[i] = CellState::Known(value)
+        }
+    }
+
+    pub(crate) fn set_unknown(&mut self, offset: i64) {
+        if self.default_zero {
+            if let Some(i) = self.ensure_offset(offset) {
+                self.cells[i] = CellState::Unknown;
+            }
+        } else if let Some(i) = self.index(offset) {
+            self.cells[i] = CellState::Default;
+        }
+    }
+
+    fn set_default(&mut self, offset: i64) {
+        if let Some(i) = self.index(offset) {
+            self.cells[i] = CellState::Default;
+        }
+    }
+
+    /// Forgets all values, e.g. after a call that may change any cell.
+    pub(crate) fn forget_all(&mut self) {
+        self.default_zero = false;
+        self.base = 0;
+        self.cells.clear();
+    }
+
+    fn ensure_offset(&mut self, offset: i64) -> Option<usize> {
+        if self.cells.is_empty() {
+            self.base = offset;
+            self.cells.push(CellState::Default);
+            return Some(0);
+        }
+
+        let start = self.base.min(offset);
+        let end = (self.base + self.cells.len() as i64 - 1).max(offset);
+        let len = (end - start + 1) as usize;
+
+        if len > MAX_FACT_CELLS {
+            self.forget_all();
+            return None;
+        }
+
+        if start != self.base {
+            let prepend = (self.base - start) as usize;
+            let mut cells = vec![CellState::Default; prepend];
+            cells.extend_from_slice(&self.cells);
+            self.cells = cells;
+            self.base = start;
+        }
+
+        if end >= self.base + self.cells.len() as i64 {
+            self.cells.resize(len, CellState::Default);
+        }
+
+        Some((offset - self.base) as usize)
+    }
+
+    /// Forgets all values but the cell at offset 0, which is zero. This is the state after a
+    /// loop, which may have moved the data pointer anywhere.
+    pub(crate) fn reset_to_current_zero(&mut self) {
+        self.default_zero = false;
+        self.base = 0;
+        self.cells.clear();
+        self.cells.push(CellState::Known(0));
+    }
+
+    /// Moves the base cell by `shift` cells.
+    pub(crate) fn rebase(&mut self, shift: i64) {
+        self.base -= shift;
+    }
+}
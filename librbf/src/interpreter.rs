@@ -0,0 +1,248 @@
+use std::ops::ControlFlow;
+
+use crate::ast::{Instruction::*, *};
+
+/// Why an [`Interpreter`] stopped before the end of the program.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Stop {
+    /// The program ran `End`.
+    End,
+    /// The data pointer or an offset from it left the tape.
+    LeftTape,
+    /// One of the [`Hooks`] stopped the program.
+    Hook,
+}
+
+/// How a loop continues after [`Hooks::iteration`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Iteration {
+    /// The interpreter runs the iteration.
+    Interpret,
+    /// The hook already ran the rest of the loop.
+    Finished,
+}
+
+/// The cells an [`Interpreter`] runs on.
+pub(crate) trait Tape {
+    fn cells(&self) -> &[u8];
+
+    fn cells_mut(&mut self) -> &mut [u8];
+
+    /// Whether `cell` is on the tape. Tapes that grow on demand make room for it first.
+    fn contains(&mut self, cell: usize) -> bool {
+        cell < self.cells().len()
+    }
+}
+
+/// What differs between the users of an [`Interpreter`]: input and output, and whatever they
+/// track or take over while the program runs.
+pub(crate) trait Hooks<'p, T> {
+    /// Called before every instruction, including the ones in loop bodies and procedures.
+    fn instruction(&mut self, _ins: &'p Instruction) -> ControlFlow<()> {
+        ControlFlow::Continue(())
+    }
+
+    /// Called before every iteration of a `Scan` or loop, with the data pointer on the cell
+    /// the loop tests. The hook may run the rest of the loop itself.
+    fn iteration(
+        &mut self,
+        _ins: &'p Instruction,
+        _tape: &mut T,
+        _ptr: &mut usize,
+    ) -> ControlFlow<(), Iteration> {
+        ControlFlow::Continue(Iteration::Interpret)
+    }
+
+    fn read(&mut self) -> u8;
+
+    fn write(&mut self, bytes: &[u8]);
+}
+
+/// The instruction semantics shared by the interpreters that are part of the compiler and the
+/// backends. [`Reference`](crate::Reference) stays separate on purpose.
+#[derive(Clone)]
+pub(crate) struct Interpreter<'p, T, H> {
+    pub(crate) tape: T,
+    pub(crate) ptr: usize,
+    pub(crate) hooks: H,
+    storage: u8,
+    procedures: [Option<&'p Program>; 256],
+}
+
+impl<T: Tape + ?Sized> Tape for &mut T {
+    fn cells(&self) -> &[u8] {
+        (**self).cells()
+    }
+
+    fn cells_mut(&mut self) -> &mut [u8] {
+        (**self).cells_mut()
+    }
+
+    fn contains(&mut self, cell: usize) -> bool {
+        (**self).contains(cell)
+    }
+}
+
+impl Tape for [u8] {
+    fn cells(&self) -> &[u8] {
+        self
+    }
+
+    fn cells_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl<'p, T: Tape, H: Hooks<'p, T>> Interpreter<'p, T, H> {
+    pub(crate) fn new(tape: T, ptr: usize, hooks: H) -> Self {
+        Interpreter {
+            tape,
+            ptr,
+            hooks,
+            storage: 0,
+            procedures: [None; 256],
+        }
+    }
+
+    pub(crate) fn execute(&mut self, program: &'p Program) -> ControlFlow<Stop> {
+        for ins in program {
+            self.instruction(ins)?;
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    /// Runs `ins`. Apart from `Scan` and loops, instructions that stop the program do so
+    /// before changing any state.
+    pub(crate) fn instruction(&mut self, ins: &'p Instruction) -> ControlFlow<Stop> {
+        hook(self.hooks.instruction(ins))?;
+
+        match *ins {
+            Add(n) => self.set(self.get().wrapping_add(n as u8)),
+            Move(n) => self.ptr = self.cell(n)?,
+            Set(n) => self.set(n as u8),
+            Mul(offset, factor) => self.transfer(offset, factor)?,
+            MulRun(ref muls) => {
+                for &(offset, _) in muls {
+                    self.cell(offset)?;
+                }
+                for &(offset, factor) in muls {
+                    self.transfer(offset, factor)?;
+                }
+                self.set(0);
+            }
+            Scan(n) => {
+                while self.get() != 0 {
+                    if self.iteration(ins)? == Iteration::Finished {
+                        break;
+                    }
+
+                    self.ptr = self.cell(n)?;
+                }
+            }
+            Read => {
+                let byte = self.hooks.read();
+                self.set(byte);
+            }
+            Write => self.hooks.write(&[self.get()]),
+            WriteConst(n) => {
+                self.set(n as u8);
+                self.hooks.write(&[n as u8]);
+            }
+            WriteBytes(ref bytes) => {
+                if let Some(&last) = bytes.last() {
+                    self.set(last);
+                }
+                self.hooks.write(bytes);
+            }
+            Loop(ref body) => {
+                while self.get() != 0 {
+                    if self.iteration(ins)? == Iteration::Finished {
+                        break;
+                    }
+
+                    self.execute(body)?;
+                }
+            }
+            BalancedLoop(ref body) => {
+                let entry = self.ptr;
+
+                while self.get() != 0 {
+                    if self.iteration(ins)? == Iteration::Finished {
+                        break;
+                    }
+
+                    for (offset, ins) in body {
+                        self.ptr = self.cell(*offset)?;
+                        self.instruction(ins)?;
+                        self.ptr = entry;
+                    }
+                }
+            }
+            Procedure(ref body) => self.procedures[self.get() as usize] = Some(body),
+            Call => {
+                if let Some(body) = self.procedures[self.get() as usize] {
+                    self.execute(body)?;
+                }
+            }
+            End => return ControlFlow::Break(Stop::End),
+            Store => self.storage = self.get(),
+            Load => self.set(self.storage),
+            Shift(n) => {
+                let value = self.get() as u32;
+                let shifted = match n {
+                    0..8 => value << n,
+                    -7..0 => value >> -n,
+                    _ => 0,
+                };
+                self.set(shifted as u8);
+            }
+            Not => self.set(!self.get()),
+            And => self.set(self.get() & self.storage),
+            Or => self.set(self.get() | self.storage),
+            Xor => self.set(self.get() ^ self.storage),
+        }
+
+        ControlFlow::Continue(())
+    }
+
+    pub(crate) fn get(&self) -> u8 {
+        self.tape.cells()[self.ptr]
+    }
+
+    fn set(&mut self, value: u8) {
+        let ptr = self.ptr;
+        self.tape.cells_mut()[ptr] = value;
+    }
+
+    fn iteration(&mut self, ins: &'p Instruction) -> ControlFlow<Stop, Iteration> {
+        hook(self.hooks.iteration(ins, &mut self.tape, &mut self.ptr))
+    }
+
+    fn transfer(&mut self, offset: i64, factor: i64) -> ControlFlow<Stop> {
+        let dest = self.cell(offset)?;
+        let value = self.get().wrapping_mul(factor as u8);
+        let cells = self.tape.cells_mut();
+        cells[dest] = cells[dest].wrapping_add(value);
+
+        ControlFlow::Continue(())
+    }
+
+    /// The index of the cell at `offset` from the current one.
+    fn cell(&mut self, offset: i64) -> ControlFlow<Stop, usize> {
+        match (self.ptr as i64)
+            .checked_add(offset)
+            .and_then(|cell| usize::try_from(cell).ok())
+        {
+            Some(cell) if self.tape.contains(cell) => ControlFlow::Continue(cell),
+            _ => ControlFlow::Break(Stop::LeftTape),
+        }
+    }
+}
+
+fn hook<C>(flow: ControlFlow<(), C>) -> ControlFlow<Stop, C> {
+    match flow {
+        ControlFlow::Continue(c) => ControlFlow::Continue(c),
+        ControlFlow::Break(()) => ControlFlow::Break(Stop::Hook),
+    }
+}
@@ -1,4 +1,4 @@
-mod common;
+pub(crate) mod common;
 
 #[cfg(all(target_arch = "aarch64", any(target_os = "linux", target_os = "macos")))]
 mod aarch64;
@@ -7,9 +7,13 @@ mod x86_64;
 
 #[cfg(all(target_arch = "aarch64", any(target_os = "linux", target_os = "macos")))]
 pub use aarch64::Jit;
-pub use common::Function;
+#[cfg(all(target_arch = "aarch64", any(target_os = "linux", target_os = "macos")))]
+use aarch64::{RELOCATION_SIZE, relocate};
+pub use common::{CompileError, Function};
 #[cfg(target_arch = "x86_64")]
 pub use x86_64::Jit;
+#[cfg(target_arch = "x86_64")]
+use x86_64::{RELOCATION_SIZE, relocate};
 
 #[cfg(not(any(
     target_arch = "x86_64",
@@ -19,16 +23,242 @@ compile_error!("rbf JIT supports only x86_64 and Unix AArch64 targets");
 
 #[cfg(test)]
 mod tests {
-    use super::Jit;
-    use crate::Instruction::WriteBytes;
+    use super::common::{Helper, Target};
+    use super::{CompileError, Jit, relocate};
+    use crate::Instruction::{Add, Loop, Move, Mul, Read, Scan, Set, Write, WriteBytes};
+    use crate::IoMode;
+
+    const SCAN_STRIDES: [i64; 10] = [1, 2, 4, 8, 3, -1, -2, -4, -8, -3];
+
+    /// Runs `Scan(stride)` compiled by `jit` from every nonzero cell of a tape with scattered
+    /// zeros and checks that it stops at the same cell as a scan one cell at a time.
+    fn check_scans(jit: fn() -> Jit, stride: i64) {
+        // The zeros at both ends stop every scan before it leaves the tape.
+        let mut tape: Vec<u8> = (0..256)
+            .map(|i| (16..240).contains(&i) && i % 37 != 0 && i * 7 % 23 != 0)
+            .map(u8::from)
+            .collect();
+        let function = jit()
+            .assume_zeroed_tape(false)
+            .compile(&vec![Scan(stride)])
+            .unwrap();
+
+        for start in 16..240 {
+            let mut expected = start;
+            while tape[expected] != 0 {
+                expected = expected.wrapping_add_signed(stride as isize);
+            }
+
+            let base = tape.as_mut_ptr();
+            let end = unsafe { function.run_from(base.add(start)) };
+
+            assert_eq!(
+                end as usize - base as usize,
+                expected,
+                "stride {}, start {}",
+                stride,
+                start
+            );
+        }
+    }
+
+    #[test]
+    fn vectorized_scans_stop_at_the_first_zero_cell() {
+        for stride in SCAN_STRIDES {
+            check_scans(Jit::new, stride);
+            check_scans(|| Jit::new().vectorize_scans(false), stride);
+        }
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    #[test]
+    fn sse2_scans_stop_at_the_first_zero_cell() {
+        for stride in SCAN_STRIDES {
+            check_scans(|| Jit::new().without_avx2(), stride);
+        }
+    }
+
+    #[test]
+    fn vectorized_scans_stay_within_the_pages_of_the_tape() {
+        const PAGE: usize = 4096;
+
+        // A tape of one page between two inaccessible ones, with zeros at both ends.
+        let pages = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                3 * PAGE,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(pages, libc::MAP_FAILED);
+        let tape = unsafe { pages.cast::<u8>().add(PAGE) };
+        assert_eq!(
+            unsafe { libc::mprotect(tape.cast(), PAGE, libc::PROT_READ | libc::PROT_WRITE) },
+            0
+        );
+        unsafe {
+            std::ptr::write_bytes(tape, 1, PAGE);
+            *tape = 0;
+            *tape.add(PAGE - 1) = 0;
+        }
+
+        for stride in SCAN_STRIDES {
+            let function = Jit::new()
+                .assume_zeroed_tape(false)
+                .compile(&vec![Scan(stride)])
+                .unwrap();
+            let (start, end) = if stride > 0 {
+                (PAGE - 1 - 100 * stride as usize, PAGE - 1)
+            } else {
+                (100 * stride.unsigned_abs() as usize, 0)
+            };
+
+            assert_eq!(
+                unsafe { function.run_from(tape.add(start)) },
+                unsafe { tape.add(end) },
+                "stride {}",
+                stride
+            );
+        }
+
+        unsafe { libc::munmap(pages, 3 * PAGE) };
+    }
 
     #[test]
     fn compiled_function_owns_bulk_write_literals() {
         let program = vec![WriteBytes(vec![b'A', b'B'])];
-        let function = Jit::new().compile(&program);
+        let function = Jit::new().compile(&program).unwrap();
 
         drop(program);
 
-        assert_eq!(function.literal_count(), 1);
+        assert_eq!(function.literals().len(), 1);
+    }
+
+    #[test]
+    fn runs_on_a_caller_owned_tape() {
+        // [->+<]> on a tape that already holds 3 at the start cell
+        let program = vec![Loop(vec![Add(-1), Move(1), Add(1), Move(-1)]), Move(1)];
+        let function = Jit::new()
+            .set_tape_size(16)
+            .assume_zeroed_tape(false)
+            .compile(&program)
+            .unwrap();
+        let mut tape = [0, 3, 4, 0];
+
+        let end = unsafe { function.run_from(tape.as_mut_ptr().add(1)) };
+
+        assert_eq!(tape, [0, 0, 7, 0]);
+        assert_eq!(end, tape[2..].as_mut_ptr());
+    }
+
+    #[test]
+    fn runs_on_a_slice() {
+        // [+>] increments every cell up to the next zero
+        let program = vec![Loop(vec![Add(1), Move(1)])];
+        let function = Jit::new()
+            .assume_zeroed_tape(false)
+            .compile(&program)
+            .unwrap();
+        let mut tape = [0, 1, 2, 3, 0, 7];
+
+        assert_eq!(function.run_on(&mut tape, 1), 4);
+        assert_eq!(tape, [0, 2, 3, 4, 0, 7]);
+    }
+
+    #[test]
+    #[should_panic(expected = "start outside the tape")]
+    fn rejects_starts_outside_the_tape() {
+        let function = Jit::new().compile(&vec![Add(1)]).unwrap();
+
+        function.run_on(&mut [0; 4], 4);
+    }
+
+    #[test]
+    fn skips_empty_bulk_writes() {
+        let program = vec![Set(7), WriteBytes(vec![])];
+        let function = Jit::new().compile(&program).unwrap();
+        let mut tape = [0; 4];
+
+        assert_eq!(function.run_on(&mut tape, 0), 0);
+        assert_eq!(tape, [7, 0, 0, 0]);
+    }
+
+    #[test]
+    fn relocations_cover_embedded_addresses() {
+        let program = vec![Add(65), Write, Read, WriteBytes(vec![b'A', b'B'])];
+        let function = Jit::new().compile(&program).unwrap();
+        let mut code = function.code().to_vec();
+
+        for relocation in function.relocations() {
+            relocate(&mut code, relocation.offset, 0);
+        }
+
+        assert_ne!(code, function.code());
+
+        for relocation in function.relocations() {
+            let address = match relocation.target {
+                Target::Helper(helper) => helper.address(),
+                Target::Literal(index) => function.literals()[index].as_ptr() as u64,
+            };
+            relocate(&mut code, relocation.offset, address);
+        }
+
+        assert_eq!(code, function.code());
+
+        // Numeric I/O calls the remaining helpers.
+        let numeric = Jit::new()
+            .set_io_mode(IoMode::Numeric)
+            .compile(&program)
+            .unwrap();
+        let targets: Vec<_> = [&function, &numeric]
+            .iter()
+            .flat_map(|function| function.relocations())
+            .map(|r| r.target)
+            .collect();
+        for helper in Helper::ALL {
+            assert!(targets.contains(&Target::Helper(helper)), "{:?}", helper);
+        }
+        assert!(targets.contains(&Target::Literal(0)));
+    }
+
+    #[test]
+    fn moves_beyond_32_bits() {
+        // Truncated to 32 bits, both moves would be negative.
+        let program = vec![Move(1 << 31), Move(1 - (1 << 31)), Add(5)];
+        let function = Jit::new().compile(&program).unwrap();
+        let mut tape = [0; 4];
+
+        assert_eq!(function.run_on(&mut tape, 0), 1);
+        assert_eq!(tape, [0, 5, 0, 0]);
+    }
+
+    #[test]
+    fn compiles_offsets_beyond_32_bits() {
+        // The loop never runs, since the first cell is zero.
+        let program = vec![
+            Loop(vec![Mul(1 << 40, 3), Mul(-(1 << 40), 5), Set(0)]),
+            Add(1),
+        ];
+        let function = Jit::new()
+            .assume_zeroed_tape(false)
+            .compile(&program)
+            .unwrap();
+        let mut tape = [0; 4];
+
+        assert_eq!(function.run_on(&mut tape, 0), 0);
+        assert_eq!(tape, [1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rejects_offsets_that_overflow() {
+        let program = vec![Move(i64::MAX), Loop(vec![Move(i64::MAX)])];
+
+        assert_eq!(
+            Jit::new().compile(&program).unwrap_err(),
+            CompileError::OffsetOverflow
+        );
     }
 }
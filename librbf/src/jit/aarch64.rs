@@ -1,176 +1,116 @@
 use super::Function;
-use super::common::{getchar, memzero, putchar};
-use crate::ast::{Instruction::*, Program};
-use crate::jit::common::putbytes;
+use super::common::{BodyCells, CompileError, Helper, Relocation, Target, check_offsets};
+use crate::ast::{Instruction, Instruction::*, Program};
+use crate::cache::CacheKey;
+use crate::facts::CellFacts;
+use crate::runtime::{
+    CONTEXT_INPUT, CONTEXT_INPUT_LIMIT, CONTEXT_INPUT_POS, CONTEXT_OUTPUT, CONTEXT_OUTPUT_LEN,
+    CONTEXT_OUTPUT_LIMIT, IoMode, numbers,
+};
 use dynasm::dynasm;
-use dynasmrt::{DynasmApi, DynasmLabelApi};
+use dynasmrt::aarch64::Aarch64Relocation;
+use dynasmrt::{DynasmApi, DynasmLabelApi, VecAssembler};
+
+/// One code pointer for each procedure number a cell can hold.
+const PROCEDURE_TABLE_SIZE: usize = 256 * 8;
+
+/// Strides of scans that compare a whole block of cells at once. They divide the block
+/// size, so every block holds the same lanes.
+const VECTOR_STRIDES: [u64; 4] = [1, 2, 4, 8];
+
+/// Version of the code this backend generates, part of every [`CacheKey`]. Bump it whenever
+/// a change makes the backend emit different code for the same program.
+const CODEGEN_VERSION: u32 = 1;
+
+/// Relocations patch the immediates of a `movz` and three `movk` instructions.
+pub(super) const RELOCATION_SIZE: usize = 16;
+
+/// Writes `address` into the relocation at `offset`.
+pub(super) fn relocate(code: &mut [u8], offset: usize, address: u64) {
+    for (i, ins) in code[offset..offset + RELOCATION_SIZE]
+        .chunks_exact_mut(4)
+        .enumerate()
+    {
+        let imm = ((address >> (16 * i)) & 0xFFFF) as u32;
+        let word = u32::from_le_bytes(ins.try_into().unwrap());
+        let word = (word & !(0xFFFF << 5)) | (imm << 5);
+        ins.copy_from_slice(&word.to_le_bytes());
+    }
+}
 
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum Reg {
     Arg0 = 0,
     Arg1 = 1,
+    Arg2 = 2,
     Scratch0 = 9,
     Scratch1 = 10,
     Scratch2 = 11,
     MulSource = 12,
+    ScanBlock = 13,
+    ScanLanes = 14,
     HelperTarget = 16,
     TapePtr = 19,
-    PutCharTarget = 20,
+    Context = 20,
     PutBytesTarget = 21,
-    GetCharTarget = 22,
+    ReadTarget = 22,
+    ProcTable = 23,
+    Storage = 24,
+    Hoisted0 = 25,
+    Hoisted1 = 26,
+    Hoisted2 = 27,
+    Hoisted3 = 28,
     FramePtr = 29,
     Link = 30,
     StackPtr = 31,
 }
 
+/// Callee-saved registers that hold cells a balanced loop only reads.
+const HOISTED_REGISTERS: [Reg; 4] = [Reg::Hoisted0, Reg::Hoisted1, Reg::Hoisted2, Reg::Hoisted3];
+
 impl From<Reg> for u8 {
     fn from(reg: Reg) -> Self {
         reg as u8
     }
 }
 
-#[derive(Clone, Copy)]
-enum CellState {
-    Default,
-    Known(u8),
-    Unknown,
-}
-
-const MAX_FACT_CELLS: usize = 4096;
-
-struct CellFacts {
-    default_zero: bool,
-    base: i64,
-    cells: Vec<CellState>,
-}
-
-impl CellFacts {
-    fn new(default_zero: bool) -> Self {
-        Self {
-            default_zero,
-            base: 0,
-            cells: Vec::new(),
-        }
-    }
-
-    fn index(&self, offset: i64) -> Option<usize> {
-        let index = offset - self.base;
-
-        (index >= 0 && (index as usize) < self.cells.len()).then_some(index as usize)
-    }
-
-    fn state(&self, offset: i64) -> CellState {
-        if let Some(index) = self.index(offset) {
-            self.cells[index]
-        } else {
-            CellState::Default
-        }
-    }
-
-    fn known(&self, offset: i64) -> Option<u8> {
-        match self.state(offset) {
-            CellState::Known(n) => Some(n),
-            CellState::Default if self.default_zero => Some(0),
-            CellState::Unknown | CellState::Default => None,
-        }
-    }
-
-    fn set_known(&mut self, offset: i64, value: u8) {
-        if self.default_zero && value == 0 {
-            self.set_default(offset);
-            return;
-        }
-        if let Some(i) = self.ensure_offset(offset) {
-            self.cells[i] = CellState::Known(value)
-        }
-    }
-
-    fn set_unknown(&mut self, offset: i64) {
-        if self.default_zero {
-            if let Some(i) = self.ensure_offset(offset) {
-                self.cells[i] = CellState::Unknown;
-            }
-        } else if let Some(i) = self.index(offset) {
-            self.cells[i] = CellState::Default;
-        }
-    }
-
-    fn set_default(&mut self, offset: i64) {
-        if let Some(i) = self.index(offset) {
-            self.cells[i] = CellState::Default;
-        }
-    }
-
-    fn forget_all(&mut self) {
-        self.default_zero = false;
-        self.base = 0;
-        self.cells.clear();
-    }
-
-    fn ensure_offset(&mut self, offset: i64) -> Option<usize> {
-        if self.cells.is_empty() {
-            self.base = offset;
-            self.cells.push(CellState::Default);
-            return Some(0);
-        }
-
-        let start = self.base.min(offset);
-        let end = (self.base + self.cells.len() as i64 - 1).max(offset);
-        let len = (end - start + 1) as usize;
-
-        if len > MAX_FACT_CELLS {
-            self.forget_all();
-            return None;
-        }
-
-        if start != self.base {
-            let prepend = (self.base - start) as usize;
-            let mut cells = vec![CellState::Default; prepend];
-            cells.extend_from_slice(&self.cells);
-            self.cells = cells;
-            self.base = start;
-        }
-
-        if end >= self.base + self.cells.len() as i64 {
-            self.cells.resize(len, CellState::Default);
-        }
-
-        Some((offset - self.base) as usize)
-    }
-
-    fn reset_to_current_zero(&mut self) {
-        self.default_zero = false;
-        self.base = 0;
-        self.cells.clear();
-        self.cells.push(CellState::Known(0));
-    }
-
-    fn rebase(&mut self, shift: i64) {
-        self.base -= shift;
-    }
-}
-
 /// Compiles brainfuck code and returns a `Function`.
 ///
 /// The AArch64 backend follows AAPCS64. The tape pointer lives in x19, which is
-/// callee-saved, so calls to Rust helper functions can use x0-x18 freely.
+/// callee-saved, so calls to Rust helper functions can use x0-x18 freely. The run context
+/// with the output buffer lives in x20, the addresses of the bulk output and input helpers
+/// in x21 and x22. The procedure
+/// table used by `Procedure` and `Call` lives in x23, the storage register of the extended
+/// instructions in x24. Balanced loops keep cells they only read in x25-x28.
 pub struct Jit {
     tape_size: usize,
-    ops: dynasmrt::aarch64::Assembler,
+    zeroed_tape: bool,
+    vectorize_scans: bool,
+    io_mode: IoMode,
+    ops: VecAssembler<Aarch64Relocation>,
     start: dynasmrt::AssemblyOffset,
+    exit: dynasmrt::DynamicLabel,
     literals: Vec<Box<[u8]>>,
+    relocations: Vec<Relocation>,
+    hoisted: Vec<(i64, Reg)>,
 }
 
 impl Jit {
     /// Initializes a `Jit` with a tape size of `30_000`.
     pub fn new() -> Jit {
-        let ops = dynasmrt::aarch64::Assembler::new().unwrap();
+        let mut ops = VecAssembler::new(0);
 
         Jit {
             tape_size: 30_000,
+            zeroed_tape: true,
+            vectorize_scans: true,
+            io_mode: IoMode::default(),
             start: ops.offset(),
+            exit: ops.new_dynamic_label(),
             ops,
             literals: Vec::new(),
+            relocations: Vec::new(),
+            hoisted: Vec::new(),
         }
     }
 
@@ -180,57 +120,121 @@ impl Jit {
         self
     }
 
+    /// Sets whether the generated code may assume that all cells are `0` when it starts.
+    /// Enabled by default, disable it for code that runs on a tape that already holds data.
+    pub fn assume_zeroed_tape(mut self, zeroed: bool) -> Self {
+        self.zeroed_tape = zeroed;
+        self
+    }
+
+    /// Sets whether scans with a stride of 1, 2, 4 or 8 compare a whole block of cells with
+    /// zero at once using NEON. Enabled by default.
+    pub fn vectorize_scans(mut self, vectorize: bool) -> Self {
+        self.vectorize_scans = vectorize;
+        self
+    }
+
+    /// Sets what `,` and `.` read and write, see [`IoMode`]. Defaults to bytes.
+    pub fn set_io_mode(mut self, mode: IoMode) -> Self {
+        self.io_mode = mode;
+        self
+    }
+
+    /// Returns a [`CacheKey`] for code compiled from `source` with these options.
+    pub fn cache_key(&self, source: &[u8]) -> CacheKey {
+        CacheKey::new(source)
+            .option("codegen", CODEGEN_VERSION)
+            .option("tape-size", self.tape_size)
+            .option("zeroed-tape", self.zeroed_tape)
+            .option("vectorize-scans", self.vectorize_scans)
+            .option("io-mode", format!("{:?}", self.io_mode))
+    }
+
     /// Generates machine code for the given program.
-    pub fn compile(mut self, program: &Program) -> Function {
+    ///
+    /// The generated function receives the data pointer in x0 and the run context in x1, and
+    /// returns the final data pointer in x0. Fails if the program's offsets overflow or the
+    /// code can't be assembled or mapped into executable memory, e.g. because a branch is out
+    /// of range.
+    pub fn compile(mut self, program: &Program) -> Result<Function, CompileError> {
+        let reach = check_offsets(program)?;
+
+        let table_size = PROCEDURE_TABLE_SIZE as u32;
+
         dynasm!(self.ops
                 ; .arch aarch64
                 ; stp X(Reg::FramePtr), X(Reg::Link), [XSP(Reg::StackPtr), #-16]!
                 ; mov XSP(Reg::FramePtr), XSP(Reg::StackPtr)
-                ; stp X(Reg::TapePtr), X(Reg::PutCharTarget), [XSP(Reg::StackPtr), #-16]!
-                ; stp X(Reg::PutBytesTarget), X(Reg::GetCharTarget), [XSP(Reg::StackPtr), #-16]!
+                ; stp X(Reg::TapePtr), X(Reg::Context), [XSP(Reg::StackPtr), #-16]!
+                ; stp X(Reg::PutBytesTarget), X(Reg::ReadTarget), [XSP(Reg::StackPtr), #-16]!
+                ; stp X(Reg::ProcTable), X(Reg::Storage), [XSP(Reg::StackPtr), #-16]!
+                ; stp X(Reg::Hoisted0), X(Reg::Hoisted1), [XSP(Reg::StackPtr), #-16]!
+                ; stp X(Reg::Hoisted2), X(Reg::Hoisted3), [XSP(Reg::StackPtr), #-16]!
+                ; mov X(Reg::Storage), xzr
+                ; mov X(Reg::TapePtr), X(Reg::Arg0)
+                ; mov X(Reg::Context), X(Reg::Arg1)
+                ; sub XSP(Reg::StackPtr), XSP(Reg::StackPtr), #table_size
+                ; mov XSP(Reg::ProcTable), XSP(Reg::StackPtr)
         );
 
-        self.load_x(Reg::Scratch0, self.tape_size as u64);
+        self.load_x(Reg::Arg1, PROCEDURE_TABLE_SIZE as u64);
+        self.load_address(Reg::HelperTarget, Target::Helper(Helper::MemZero));
         dynasm!(self.ops
                 ; .arch aarch64
-                ; sub XSP(Reg::StackPtr), XSP(Reg::StackPtr), X(Reg::Scratch0)
-                ; mov XSP(Reg::TapePtr), XSP(Reg::StackPtr)
-        );
-
-        self.load_x(Reg::Arg1, self.tape_size as u64);
-        self.load_x(Reg::HelperTarget, memzero as *const () as u64);
-        dynasm!(self.ops
-                ; .arch aarch64
-                ; mov X(Reg::Arg0), X(Reg::TapePtr)
+                ; mov X(Reg::Arg0), X(Reg::ProcTable)
                 ; blr X(Reg::HelperTarget)
         );
 
-        self.load_x(Reg::PutCharTarget, putchar as *const () as u64);
-        self.load_x(Reg::PutBytesTarget, putbytes as *const () as u64);
-        self.load_x(Reg::GetCharTarget, getchar as *const () as u64);
+        self.load_address(Reg::PutBytesTarget, Target::Helper(Helper::PutBytes));
+        let read = match self.io_mode {
+            IoMode::Bytes => Helper::GetChar,
+            IoMode::Numeric => Helper::GetNumber,
+        };
+        self.load_address(Reg::ReadTarget, Target::Helper(read));
 
         self.generate(program);
 
-        self.load_x(Reg::Scratch0, self.tape_size as u64);
+        // The epilogue is also reached by `End` from inside procedures, so the stack pointer
+        // is restored from the frame pointer instead of popping the procedure table.
+        let exit = self.exit;
         dynasm!(self.ops
                 ; .arch aarch64
-                ; add XSP(Reg::StackPtr), XSP(Reg::StackPtr), X(Reg::Scratch0)
-        );
-
-        dynasm!(self.ops
-                ; .arch aarch64
-                ; ldp X(Reg::PutBytesTarget), X(Reg::GetCharTarget), [XSP(Reg::StackPtr)], #16
-                ; ldp X(Reg::TapePtr), X(Reg::PutCharTarget), [XSP(Reg::StackPtr)], #16
+                ; =>exit
+                ; mov X(Reg::Arg0), X(Reg::TapePtr)
+                ; sub XSP(Reg::StackPtr), XSP(Reg::FramePtr), #80
+                ; ldp X(Reg::Hoisted2), X(Reg::Hoisted3), [XSP(Reg::StackPtr)], #16
+                ; ldp X(Reg::Hoisted0), X(Reg::Hoisted1), [XSP(Reg::StackPtr)], #16
+                ; ldp X(Reg::ProcTable), X(Reg::Storage), [XSP(Reg::StackPtr)], #16
+                ; ldp X(Reg::PutBytesTarget), X(Reg::ReadTarget), [XSP(Reg::StackPtr)], #16
+                ; ldp X(Reg::TapePtr), X(Reg::Context), [XSP(Reg::StackPtr)], #16
                 ; ldp X(Reg::FramePtr), X(Reg::Link), [XSP(Reg::StackPtr)], #16
                 ; ret
         );
 
-        let buf = self.ops.finalize().unwrap();
-        Function::new(buf, self.start, self.literals)
+        let code = self.ops.finalize().map_err(CompileError::Assemble)?;
+        Function::load(
+            &code,
+            self.start.0,
+            self.tape_size,
+            reach,
+            self.literals,
+            self.relocations,
+        )
+        .map_err(|e| CompileError::Memory(e.kind()))
+    }
+
+    /// Generates machine code for a single loop with the given body.
+    ///
+    /// The loop runs on a tape that already holds data, starting at the pointer passed to
+    /// [`Function::run_from`] and returning the data pointer once the loop is left. This lets
+    /// an interpreter hand hot loops over to native code.
+    pub fn compile_loop(self, body: &Program) -> Result<Function, CompileError> {
+        self.assume_zeroed_tape(false)
+            .compile(&vec![Loop(body.clone())])
     }
 
     fn generate(&mut self, program: &Program) {
-        self.generate_with_facts(program, true);
+        self.generate_with_facts(program, self.zeroed_tape);
     }
 
     fn generate_with_facts(&mut self, program: &Program, default_zero: bool) {
@@ -346,7 +350,10 @@ impl Jit {
                     self.write_byte(value);
                 }
                 WriteBytes(bytes) => {
-                    let last = *bytes.last().unwrap();
+                    // Writing no bytes leaves the cell alone.
+                    let Some(&last) = bytes.last() else {
+                        continue;
+                    };
 
                     if facts.known(offset) != Some(last) {
                         self.set(offset, last as i64);
@@ -374,6 +381,69 @@ impl Jit {
 
                     facts.reset_to_current_zero();
                 }
+                BalancedLoop(body) => {
+                    if facts.known(offset) == Some(0) {
+                        continue;
+                    }
+
+                    // The data pointer stays put, so only the cells written in the body change.
+                    self.balanced_loop(offset, body);
+
+                    for cell in BodyCells::of(body).writes {
+                        facts.set_unknown(offset + cell);
+                    }
+
+                    facts.set_known(offset, 0);
+                }
+                Procedure(body) => self.procedure(offset, body),
+                Call => {
+                    // The procedure may change any cell, so nothing is known afterwards.
+                    self.flush_offset(&mut offset, &mut facts);
+                    self.call();
+                    facts.forget_all();
+                }
+                End => {
+                    self.flush_offset(&mut offset, &mut facts);
+                    self.end();
+                }
+                Store => {
+                    if let Some(value) = facts.known(offset) {
+                        self.load_x(Reg::Storage, value as u64);
+                    } else {
+                        self.store(offset);
+                    }
+                }
+                Load => {
+                    self.load(offset);
+                    facts.set_unknown(offset);
+                }
+                &Shift(n) => {
+                    if let Some(old) = facts.known(offset) {
+                        let new = shift(old, n);
+
+                        if new != old {
+                            self.set(offset, new as i64);
+                        }
+
+                        facts.set_known(offset, new);
+                    } else {
+                        self.shift(offset, n);
+                        facts.set_unknown(offset);
+                    }
+                }
+                Not => {
+                    if let Some(old) = facts.known(offset) {
+                        self.set(offset, !old as i64);
+                        facts.set_known(offset, !old);
+                    } else {
+                        self.bitwise(offset, &Not);
+                        facts.set_unknown(offset);
+                    }
+                }
+                And | Or | Xor => {
+                    self.bitwise(offset, ins);
+                    facts.set_unknown(offset);
+                }
             }
         }
 
@@ -386,22 +456,6 @@ impl Jit {
         for ins in program.iter() {
             match ins {
                 &Move(i) => offset += i,
-                &Add(n) => self.add(offset, n),
-                &Set(n) => self.set(offset, n),
-                &Mul(o, f) => self.mul(offset, offset + o, f),
-                MulRun(muls) => self.mul_run(offset, muls),
-                Write => self.write(offset),
-                Read => self.read(offset),
-                &WriteConst(n) => {
-                    let value = n as u8;
-                    self.set(offset, value as i64);
-                    self.write_byte(value);
-                }
-                WriteBytes(bytes) => {
-                    let last = *bytes.last().unwrap();
-                    self.set(offset, last as i64);
-                    self.write_bytes(bytes);
-                }
                 &Scan(n) => {
                     self.flush_offset_without_facts(&mut offset);
                     self.scan(n);
@@ -410,12 +464,54 @@ impl Jit {
                     self.flush_offset_without_facts(&mut offset);
                     self.r#loop(body);
                 }
+                Procedure(body) => self.procedure(offset, body),
+                Call => {
+                    self.flush_offset_without_facts(&mut offset);
+                    self.call();
+                }
+                End => {
+                    self.flush_offset_without_facts(&mut offset);
+                    self.end();
+                }
+                ins => self.instruction(offset, ins),
             }
         }
 
         self.flush_offset_without_facts(&mut offset);
     }
 
+    /// Generates code for an instruction that stays in place, working on the cell at `offset`.
+    fn instruction(&mut self, offset: i64, ins: &Instruction) {
+        match ins {
+            &Add(n) => self.add(offset, n),
+            &Set(n) => self.set(offset, n),
+            &Mul(o, f) => self.mul(offset, offset + o, f),
+            MulRun(muls) => self.mul_run(offset, muls),
+            Write => self.write(offset),
+            Read => self.read(offset),
+            &WriteConst(n) => {
+                let value = n as u8;
+                self.set(offset, value as i64);
+                self.write_byte(value);
+            }
+            WriteBytes(bytes) => {
+                // Writing no bytes leaves the cell alone.
+                if let Some(&last) = bytes.last() {
+                    self.set(offset, last as i64);
+                    self.write_bytes(bytes);
+                }
+            }
+            BalancedLoop(body) => self.balanced_loop(offset, body),
+            Store => self.store(offset),
+            Load => self.load(offset),
+            &Shift(n) => self.shift(offset, n),
+            Not | And | Or | Xor => self.bitwise(offset, ins),
+            Move(_) | Scan(_) | Loop(_) | Procedure(_) | Call | End => {
+                unreachable!("instruction moves the data pointer: {:?}", ins)
+            }
+        }
+    }
+
     /// Flushes the offset to the tape pointer and resets it to 0.
     fn flush_offset(&mut self, offset: &mut i64, facts: &mut CellFacts) {
         let shift = *offset;
@@ -432,6 +528,14 @@ impl Jit {
     fn load_cell(&mut self, dst: Reg, scratch: Reg, offset: i64) {
         debug_assert!(dst != scratch);
 
+        if let Some(&(_, reg)) = self.hoisted.iter().find(|&&(cell, _)| cell == offset) {
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; mov W(dst), W(reg)
+            );
+            return;
+        }
+
         if let Some(offset) = direct_byte_offset(offset) {
             dynasm!(self.ops
                 ; .arch aarch64
@@ -535,47 +639,115 @@ impl Jit {
     }
 
     fn write(&mut self, offset: i64) {
-        self.load_cell(Reg::Arg0, Reg::Scratch2, offset);
-        dynasm!(self.ops
-            ; .arch aarch64
-            ; blr X(Reg::PutCharTarget)
-        );
+        self.load_cell(Reg::Arg1, Reg::Scratch2, offset);
+        self.put_byte();
     }
 
+    /// Takes the next byte from the input buffer of the run context, or gets it from the
+    /// input helper if the buffer is used up, input is interactive or numeric.
     fn read(&mut self, offset: i64) {
+        let slow = self.ops.new_dynamic_label();
+        let done = self.ops.new_dynamic_label();
+        let input = CONTEXT_INPUT as u32;
+        let pos = CONTEXT_INPUT_POS as u32;
+        let limit = CONTEXT_INPUT_LIMIT as u32;
+
+        if self.io_mode == IoMode::Bytes {
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; ldr X(Reg::Scratch0), [X(Reg::Context), #pos]
+                ; ldr X(Reg::Scratch1), [X(Reg::Context), #limit]
+                ; cmp X(Reg::Scratch0), X(Reg::Scratch1)
+                ; b.hs =>slow
+                ; ldr X(Reg::Scratch1), [X(Reg::Context), #input]
+                ; ldrb W(Reg::Arg0), [X(Reg::Scratch1), X(Reg::Scratch0)]
+                ; add XSP(Reg::Scratch0), XSP(Reg::Scratch0), #1
+                ; str X(Reg::Scratch0), [X(Reg::Context), #pos]
+                ; b =>done
+            );
+        }
+
         dynasm!(self.ops
             ; .arch aarch64
-            ; blr X(Reg::GetCharTarget)
+            ; =>slow
+            ; mov X(Reg::Arg0), X(Reg::Context)
+            ; blr X(Reg::ReadTarget)
+            ; =>done
         );
         self.store_cell(Reg::Arg0, Reg::Scratch2, offset);
     }
 
     fn write_byte(&mut self, byte: u8) {
-        self.load_x(Reg::Arg0, byte as u64);
+        if self.io_mode == IoMode::Numeric {
+            self.write_bytes(&[byte]);
+            return;
+        }
+
+        self.load_x(Reg::Arg1, byte as u64);
+        self.put_byte();
+    }
+
+    /// Appends the byte in w1 to the output buffer of the run context, or hands it to
+    /// `putchar` if the buffer is full or output has to be flushed. Numeric I/O formats it
+    /// with `putnumber` instead.
+    fn put_byte(&mut self) {
+        if self.io_mode == IoMode::Numeric {
+            self.load_address(Reg::HelperTarget, Target::Helper(Helper::PutNumber));
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; mov X(Reg::Arg0), X(Reg::Context)
+                ; blr X(Reg::HelperTarget)
+            );
+            return;
+        }
+
+        let slow = self.ops.new_dynamic_label();
+        let done = self.ops.new_dynamic_label();
+        let output = CONTEXT_OUTPUT as u32;
+        let len = CONTEXT_OUTPUT_LEN as u32;
+        let limit = CONTEXT_OUTPUT_LIMIT as u32;
+
         dynasm!(self.ops
             ; .arch aarch64
-            ; blr X(Reg::PutCharTarget)
+            ; ldr X(Reg::Scratch0), [X(Reg::Context), #len]
+            ; ldr X(Reg::Scratch1), [X(Reg::Context), #limit]
+            ; cmp X(Reg::Scratch0), X(Reg::Scratch1)
+            ; b.hs =>slow
+            ; ldr X(Reg::Scratch1), [X(Reg::Context), #output]
+            ; strb W(Reg::Arg1), [X(Reg::Scratch1), X(Reg::Scratch0)]
+            ; add XSP(Reg::Scratch0), XSP(Reg::Scratch0), #1
+            ; str X(Reg::Scratch0), [X(Reg::Context), #len]
+            ; b =>done
+            ; =>slow
+        );
+        self.load_address(Reg::HelperTarget, Target::Helper(Helper::PutChar));
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; mov X(Reg::Arg0), X(Reg::Context)
+            ; blr X(Reg::HelperTarget)
+            ; =>done
         );
     }
 
     fn write_bytes(&mut self, bytes: &[u8]) {
-        let (ptr, len) = self.retain_bytes(bytes);
+        let len = match self.io_mode {
+            IoMode::Bytes => self.retain_bytes(bytes),
+            IoMode::Numeric => self.retain_bytes(&numbers(bytes)),
+        };
 
-        self.load_x(Reg::Arg0, ptr as u64);
-        self.load_x(Reg::Arg1, len as u64);
+        self.load_address(Reg::Arg1, Target::Literal(self.literals.len() - 1));
+        self.load_x(Reg::Arg2, len as u64);
 
         dynasm!(self.ops
             ; .arch aarch64
+            ; mov X(Reg::Arg0), X(Reg::Context)
             ; blr X(Reg::PutBytesTarget)
         );
     }
 
-    fn retain_bytes(&mut self, bytes: &[u8]) -> (*const u8, usize) {
-        let bytes = bytes.to_vec().into_boxed_slice();
-        let ptr = bytes.as_ptr();
-        let len = bytes.len();
-        self.literals.push(bytes);
-        (ptr, len)
+    fn retain_bytes(&mut self, bytes: &[u8]) -> usize {
+        self.literals.push(bytes.to_vec().into_boxed_slice());
+        bytes.len()
     }
 
     /// Generates code for `Instruction::Mul`.
@@ -667,26 +839,155 @@ impl Jit {
     }
 
     fn scan(&mut self, n: i64) {
-        let move_label = self.ops.new_dynamic_label();
         let rest_label = self.ops.new_dynamic_label();
 
         dynasm!(self.ops
             ; .arch aarch64
             ; ldrb W(Reg::Scratch0), [X(Reg::TapePtr)]
             ; cbz W(Reg::Scratch0), =>rest_label
-            ; =>move_label
         );
 
-        self.move_tape(n);
+        if self.vectorize_scans && VECTOR_STRIDES.contains(&n.unsigned_abs()) {
+            self.vector_scan(n);
+        } else {
+            let move_label = self.ops.new_dynamic_label();
+
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; =>move_label
+            );
+
+            self.move_tape(n);
+
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; ldrb W(Reg::Scratch0), [X(Reg::TapePtr)]
+                ; cbnz W(Reg::Scratch0), =>move_label
+            );
+        }
 
         dynasm!(self.ops
             ; .arch aarch64
-            ; ldrb W(Reg::Scratch0), [X(Reg::TapePtr)]
-            ; cbnz W(Reg::Scratch0), =>move_label
             ; =>rest_label
         );
     }
 
+    /// Generates a scan that compares aligned blocks of 16 cells with zero at once and picks
+    /// the first zero cell in the direction of the scan among the cells the stride reaches.
+    /// The current cell is known not to be zero.
+    ///
+    /// Aligned blocks never cross a page boundary, so the scan only touches pages that hold a
+    /// cell it would also reach one cell at a time. Cells before the data pointer in the
+    /// first block are masked out.
+    fn vector_scan(&mut self, stride: i64) {
+        let step = stride.unsigned_abs();
+        // Four bits per cell of a block, set for the cells a stride starting at cell 0 reaches.
+        let lanes = (0..16)
+            .step_by(step as usize)
+            .fold(0u64, |lanes, cell| lanes | 0xF << (4 * cell));
+        let block_label = self.ops.new_dynamic_label();
+        let found_label = self.ops.new_dynamic_label();
+
+        // The lanes of the cells the stride reaches from the data pointer.
+        self.load_x(Reg::ScanLanes, lanes);
+
+        if step > 1 {
+            let mask = step - 1;
+
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; and XSP(Reg::Scratch0), X(Reg::TapePtr), #mask
+                ; lsl X(Reg::Scratch0), X(Reg::Scratch0), #2
+                ; lsl X(Reg::ScanLanes), X(Reg::ScanLanes), X(Reg::Scratch0)
+            );
+        }
+
+        // x10 masks out the cells of the first block that lie behind the data pointer.
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; and XSP(Reg::Scratch0), X(Reg::TapePtr), #15
+            ; lsl X(Reg::Scratch0), X(Reg::Scratch0), #2
+            ; movn X(Reg::Scratch1), #0
+        );
+
+        if stride > 0 {
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; lsl X(Reg::Scratch1), X(Reg::Scratch1), X(Reg::Scratch0)
+            );
+        } else {
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; eor XSP(Reg::Scratch0), X(Reg::Scratch0), #60
+                ; lsr X(Reg::Scratch1), X(Reg::Scratch1), X(Reg::Scratch0)
+            );
+        }
+
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; and X(Reg::Scratch1), X(Reg::Scratch1), X(Reg::ScanLanes)
+            ; and XSP(Reg::ScanBlock), X(Reg::TapePtr), #0xFFFF_FFFF_FFFF_FFF0
+        );
+        self.compare_block_zero();
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; ands X(Reg::Scratch0), X(Reg::Scratch0), X(Reg::Scratch1)
+            ; b.ne =>found_label
+            ; =>block_label
+        );
+
+        if stride > 0 {
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; add XSP(Reg::ScanBlock), XSP(Reg::ScanBlock), #16
+            );
+        } else {
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; sub XSP(Reg::ScanBlock), XSP(Reg::ScanBlock), #16
+            );
+        }
+
+        self.compare_block_zero();
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; ands X(Reg::Scratch0), X(Reg::Scratch0), X(Reg::ScanLanes)
+            ; b.eq =>block_label
+            ; =>found_label
+        );
+
+        // Turn the lowest or highest set bit into the index of its cell.
+        if stride > 0 {
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; rbit X(Reg::Scratch0), X(Reg::Scratch0)
+                ; clz X(Reg::Scratch0), X(Reg::Scratch0)
+            );
+        } else {
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; clz X(Reg::Scratch0), X(Reg::Scratch0)
+                ; eor XSP(Reg::Scratch0), X(Reg::Scratch0), #63
+            );
+        }
+
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; add X(Reg::TapePtr), X(Reg::ScanBlock), X(Reg::Scratch0), lsr #2
+        );
+    }
+
+    /// Sets four bits in x9 for each cell of the aligned block at x13 that is `0`.
+    fn compare_block_zero(&mut self) {
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; ldr q0, [X(Reg::ScanBlock)]
+            ; cmeq v0.b16, v0.b16, #0
+            ; shrn v0.b8, v0.h8, #4
+            ; fmov X(Reg::Scratch0), d0
+        );
+    }
+
     fn r#loop(&mut self, body: &Program) {
         let body_label = self.ops.new_dynamic_label();
         let rest_label = self.ops.new_dynamic_label();
@@ -708,6 +1009,174 @@ impl Jit {
         );
     }
 
+    /// Generates code for `Instruction::BalancedLoop` on the cell at `base`.
+    ///
+    /// The body is addressed relative to `base` without moving the tape pointer, and cells it
+    /// only reads are loaded into callee-saved registers once, before the first iteration.
+    fn balanced_loop(&mut self, base: i64, body: &[(i64, Instruction)]) {
+        assert!(
+            body.iter().all(|(_, ins)| ins.stays_in_place()),
+            "balanced loop bodies can't move the data pointer"
+        );
+
+        let body_label = self.ops.new_dynamic_label();
+        let rest_label = self.ops.new_dynamic_label();
+
+        self.load_cell(Reg::Scratch0, Reg::Scratch2, base);
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; cbz W(Reg::Scratch0), =>rest_label
+        );
+
+        let hoisted = self.hoisted.len();
+
+        for cell in BodyCells::of(body).invariant() {
+            let cell = base + cell;
+            let free = HOISTED_REGISTERS
+                .into_iter()
+                .find(|&reg| self.hoisted.iter().all(|&(_, r)| r != reg));
+
+            if self.hoisted.iter().any(|&(c, _)| c == cell) {
+                continue;
+            }
+
+            let Some(reg) = free else {
+                break;
+            };
+
+            self.load_cell(reg, Reg::Scratch2, cell);
+            self.hoisted.push((cell, reg));
+        }
+
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; =>body_label
+        );
+
+        for (offset, ins) in body {
+            self.instruction(base + offset, ins);
+        }
+
+        self.load_cell(Reg::Scratch0, Reg::Scratch2, base);
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; cbnz W(Reg::Scratch0), =>body_label
+            ; =>rest_label
+        );
+
+        self.hoisted.truncate(hoisted);
+    }
+
+    /// Generates code for `Instruction::Procedure`.
+    ///
+    /// The body is emitted inline and skipped over. Reaching the definition stores the body's
+    /// address in the procedure table slot selected by the cell at `offset`.
+    fn procedure(&mut self, offset: i64, body: &Program) {
+        let body_label = self.ops.new_dynamic_label();
+        let rest_label = self.ops.new_dynamic_label();
+
+        self.load_cell(Reg::Scratch0, Reg::Scratch2, offset);
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; adr X(Reg::Scratch1), =>body_label
+            ; str X(Reg::Scratch1), [X(Reg::ProcTable), X(Reg::Scratch0), lsl #3]
+            ; b =>rest_label
+            ; =>body_label
+            ; stp X(Reg::FramePtr), X(Reg::Link), [XSP(Reg::StackPtr), #-16]!
+        );
+
+        self.generate_without_facts(body);
+
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; ldp X(Reg::FramePtr), X(Reg::Link), [XSP(Reg::StackPtr)], #16
+            ; ret
+            ; =>rest_label
+        );
+    }
+
+    /// Generates code for `Instruction::Call`, skipping undefined procedures.
+    fn call(&mut self) {
+        let rest_label = self.ops.new_dynamic_label();
+
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; ldrb W(Reg::Scratch0), [X(Reg::TapePtr)]
+            ; ldr X(Reg::HelperTarget), [X(Reg::ProcTable), X(Reg::Scratch0), lsl #3]
+            ; cbz X(Reg::HelperTarget), =>rest_label
+            ; blr X(Reg::HelperTarget)
+            ; =>rest_label
+        );
+    }
+
+    fn end(&mut self) {
+        let exit = self.exit;
+
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; b =>exit
+        );
+    }
+
+    fn store(&mut self, offset: i64) {
+        self.load_cell(Reg::Storage, Reg::Scratch2, offset);
+    }
+
+    fn load(&mut self, offset: i64) {
+        self.store_cell(Reg::Storage, Reg::Scratch2, offset);
+    }
+
+    fn shift(&mut self, offset: i64, n: i64) {
+        if !(-7..8).contains(&n) {
+            self.zero_cell(Reg::Scratch2, offset);
+            return;
+        }
+
+        let amount = n.unsigned_abs() as u32;
+
+        self.load_cell(Reg::Scratch0, Reg::Scratch2, offset);
+        if n > 0 {
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; lsl W(Reg::Scratch0), W(Reg::Scratch0), #amount
+            );
+        } else {
+            dynasm!(self.ops
+                ; .arch aarch64
+                ; lsr W(Reg::Scratch0), W(Reg::Scratch0), #amount
+            );
+        }
+        self.store_cell(Reg::Scratch0, Reg::Scratch2, offset);
+    }
+
+    /// Generates code for `Not` and the `And`, `Or` and `Xor` instructions that combine the
+    /// cell with the storage register.
+    fn bitwise(&mut self, offset: i64, op: &Instruction) {
+        self.load_cell(Reg::Scratch0, Reg::Scratch2, offset);
+
+        match op {
+            Not => dynasm!(self.ops
+                ; .arch aarch64
+                ; mvn W(Reg::Scratch0), W(Reg::Scratch0)
+            ),
+            And => dynasm!(self.ops
+                ; .arch aarch64
+                ; and W(Reg::Scratch0), W(Reg::Scratch0), W(Reg::Storage)
+            ),
+            Or => dynasm!(self.ops
+                ; .arch aarch64
+                ; orr W(Reg::Scratch0), W(Reg::Scratch0), W(Reg::Storage)
+            ),
+            Xor => dynasm!(self.ops
+                ; .arch aarch64
+                ; eor W(Reg::Scratch0), W(Reg::Scratch0), W(Reg::Storage)
+            ),
+            _ => unreachable!("not a bitwise instruction: {:?}", op),
+        }
+
+        self.store_cell(Reg::Scratch0, Reg::Scratch2, offset);
+    }
+
     fn move_tape(&mut self, offset: i64) {
         if offset == 0 {
             return;
@@ -797,6 +1266,30 @@ impl Jit {
         }
     }
 
+    /// Loads the address of `target` with a fixed-length sequence and records it as a
+    /// relocation, so the code can be patched when it's loaded from a cache.
+    fn load_address(&mut self, reg: Reg, target: Target) {
+        let address = match target {
+            Target::Helper(helper) => helper.address(),
+            Target::Literal(index) => self.literals[index].as_ptr() as u64,
+        };
+        let offset = self.ops.offset().0;
+        let p0 = (address & 0xFFFF) as u32;
+        let p1 = ((address >> 16) & 0xFFFF) as u32;
+        let p2 = ((address >> 32) & 0xFFFF) as u32;
+        let p3 = ((address >> 48) & 0xFFFF) as u32;
+
+        dynasm!(self.ops
+            ; .arch aarch64
+            ; movz X(reg), #p0
+            ; movk X(reg), #p1, lsl #16
+            ; movk X(reg), #p2, lsl #32
+            ; movk X(reg), #p3, lsl #48
+        );
+
+        self.relocations.push(Relocation { offset, target });
+    }
+
     fn load_x(&mut self, reg: Reg, value: u64) {
         let p0 = (value & 0xFFFF) as u32;
         let p1 = ((value >> 16) & 0xFFFF) as u32;
@@ -835,6 +1328,14 @@ impl Default for Jit {
     }
 }
 
+fn shift(value: u8, amount: i64) -> u8 {
+    match amount {
+        0..8 => value << amount,
+        -7..0 => value >> -amount,
+        _ => 0,
+    }
+}
+
 fn direct_byte_offset(offset: i64) -> Option<u32> {
     if (0..4096).contains(&offset) {
         Some(offset as u32)
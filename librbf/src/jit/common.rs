@@ -1,60 +1,515 @@
-use dynasmrt::{AssemblyOffset, ExecutableBuffer};
-use std::io::Read;
-use std::io::{self, Write};
-use std::mem;
+use crate::ast::{Instruction, Instruction::*, Program};
+use crate::runtime::{Context, Flush, OutputFormat};
+use dynasmrt::mmap::MutableBuffer;
+use dynasmrt::{AssemblyOffset, DynasmError, ExecutableBuffer, cache_control};
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::{fmt, io, mem};
 
-pub(crate) extern "C" fn putchar(c: u8) {
-    print!("{}", c as char);
+/// Why a program couldn't be compiled.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum CompileError {
+    /// The moves and offsets of the program add up to more cells than an address can reach.
+    OffsetOverflow,
+    /// The generated code couldn't be assembled, e.g. because a jump is out of range.
+    Assemble(DynasmError),
+    /// The generated code couldn't be mapped into executable memory.
+    Memory(io::ErrorKind),
 }
 
-pub(crate) extern "C" fn putbytes(buf: *const u8, count: u64) {
-    let bytes = unsafe { std::slice::from_raw_parts(buf, count as usize) };
-    std::io::stdout().write_all(bytes).unwrap();
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CompileError::OffsetOverflow => write!(f, "cell offsets overflow"),
+            CompileError::Assemble(e) => write!(f, "could not assemble the code: {}", e),
+            CompileError::Memory(kind) => write!(f, "could not map the code: {}", kind),
+        }
+    }
+}
+
+impl Error for CompileError {}
+
+/// Checks that no offset the backends compute from `program` overflows, and returns the sum of
+/// the magnitudes of all its moves and offsets.
+///
+/// The backends add up moves and offsets while generating code, but never to more than this
+/// sum. It also bounds how far the data pointer moves between two accesses to the tape, which
+/// [`GuardedTape`] relies on.
+pub(crate) fn check_offsets(program: &Program) -> Result<u64, CompileError> {
+    reach(program)
+        .filter(|&reach| reach <= i64::MAX as u64)
+        .ok_or(CompileError::OffsetOverflow)
+}
+
+/// The sum of the magnitudes of all moves and offsets in `program`, if it fits in a `u64`.
+fn reach(program: &[Instruction]) -> Option<u64> {
+    program
+        .iter()
+        .try_fold(0u64, |sum, ins| sum.checked_add(instruction_reach(ins)?))
 }
 
-pub(crate) extern "C" fn getchar() -> u8 {
-    io::stdout().flush().unwrap();
-    let mut buf = [0];
-    match io::stdin().lock().read(&mut buf) {
-        Ok(1) => buf[0],
-        _ => 0,
+fn instruction_reach(ins: &Instruction) -> Option<u64> {
+    match ins {
+        &Move(n) | &Scan(n) | &Mul(n, _) => Some(n.unsigned_abs()),
+        MulRun(muls) => muls
+            .iter()
+            .try_fold(0u64, |sum, &(n, _)| sum.checked_add(n.unsigned_abs())),
+        Loop(body) | Procedure(body) => reach(body),
+        BalancedLoop(body) => body.iter().try_fold(0u64, |sum, (offset, ins)| {
+            sum.checked_add(offset.unsigned_abs())?
+                .checked_add(instruction_reach(ins)?)
+        }),
+        _ => Some(0),
     }
 }
 
+pub(crate) extern "C" fn putchar(context: &mut Context, c: u8) {
+    context.write(&[c]);
+}
+
+pub(crate) extern "C" fn putbytes(context: &mut Context, buf: *const u8, count: u64) {
+    context.write(unsafe { std::slice::from_raw_parts(buf, count as usize) });
+}
+
+pub(crate) extern "C" fn getchar(context: &mut Context) -> u8 {
+    context.read()
+}
+
+pub(crate) extern "C" fn putnumber(context: &mut Context, c: u8) {
+    context.write_number(c);
+}
+
+pub(crate) extern "C" fn getnumber(context: &mut Context) -> u8 {
+    context.read_number()
+}
+
 pub(crate) extern "C" fn memzero(dst: *mut u8, count: usize) {
     unsafe { std::ptr::write_bytes(dst, 0, count) }
 }
 
+/// A Rust function called by generated code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Helper {
+    PutChar,
+    PutBytes,
+    GetChar,
+    MemZero,
+    PutNumber,
+    GetNumber,
+}
+
+impl Helper {
+    pub(crate) const ALL: [Helper; 6] = [
+        Helper::PutChar,
+        Helper::PutBytes,
+        Helper::GetChar,
+        Helper::MemZero,
+        Helper::PutNumber,
+        Helper::GetNumber,
+    ];
+
+    pub(crate) fn address(self) -> u64 {
+        match self {
+            Helper::PutChar => putchar as *const () as u64,
+            Helper::PutBytes => putbytes as *const () as u64,
+            Helper::GetChar => getchar as *const () as u64,
+            Helper::MemZero => memzero as *const () as u64,
+            Helper::PutNumber => putnumber as *const () as u64,
+            Helper::GetNumber => getnumber as *const () as u64,
+        }
+    }
+}
+
+/// What an absolute address embedded in generated code points to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Target {
+    Helper(Helper),
+    /// The byte literal with this index.
+    Literal(usize),
+}
+
+/// An absolute address at `offset` in the generated code, which has to be patched when the
+/// code is loaded into another process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Relocation {
+    pub(crate) offset: usize,
+    pub(crate) target: Target,
+}
+
+/// The cells a balanced loop body reads and writes, as offsets from the loop's cell.
+#[derive(Default)]
+pub(crate) struct BodyCells {
+    pub(crate) reads: Vec<i64>,
+    pub(crate) writes: Vec<i64>,
+}
+
+impl BodyCells {
+    pub(crate) fn of(body: &[(i64, Instruction)]) -> BodyCells {
+        let mut cells = BodyCells::default();
+        cells.collect(0, body);
+        cells
+    }
+
+    /// Cells the body reads but never writes, which keep their value for the whole loop.
+    /// The most frequently read cells come first.
+    pub(crate) fn invariant(&self) -> Vec<i64> {
+        let mut counts = BTreeMap::<i64, usize>::new();
+
+        for &cell in self.reads.iter().filter(|cell| !self.writes.contains(cell)) {
+            *counts.entry(cell).or_default() += 1;
+        }
+
+        let mut cells: Vec<_> = counts.into_iter().collect();
+        cells.sort_by_key(|&(cell, count)| (Reverse(count), cell));
+        cells.into_iter().map(|(cell, _)| cell).collect()
+    }
+
+    fn collect(&mut self, base: i64, body: &[(i64, Instruction)]) {
+        for (offset, ins) in body {
+            let cell = base + offset;
+
+            match ins {
+                Write | Store => self.reads.push(cell),
+                &Mul(dest, _) => {
+                    self.reads.push(cell);
+                    self.writes.push(cell + dest);
+                }
+                MulRun(muls) => {
+                    self.writes.push(cell);
+                    self.writes
+                        .extend(muls.iter().map(|&(dest, _)| cell + dest));
+                }
+                BalancedLoop(body) => {
+                    self.reads.push(cell);
+                    self.collect(cell, body);
+                }
+                _ => self.writes.push(cell),
+            }
+        }
+    }
+}
+
+/// A tape between two inaccessible regions of memory, so that generated code that moves off the
+/// tape faults instead of overwriting memory outside of it.
+///
+/// The generated code doesn't check moves, but between two accesses to the tape the data
+/// pointer moves by at most the program's reach, and every access is at most that far from the
+/// data pointer. Guards of three times the reach therefore catch the first access outside the
+/// tape. The rest of the tape's last page is writable too, and starts out zeroed.
+pub(crate) struct GuardedTape {
+    pages: *mut libc::c_void,
+    mapped: usize,
+    cells: *mut u8,
+    len: usize,
+}
+
+impl GuardedTape {
+    /// Maps a zeroed tape of `len` cells for code with the given reach.
+    pub(crate) fn new(len: usize, reach: u64) -> io::Result<Self> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let round_up = |size: usize| size.checked_next_multiple_of(page);
+        let too_large = || io::Error::new(io::ErrorKind::OutOfMemory, "tape guards too large");
+
+        // A little extra for vectorized scans, which read whole blocks of cells.
+        let guard = reach
+            .checked_mul(3)
+            .and_then(|size| size.checked_add(64))
+            .and_then(|size| usize::try_from(size).ok())
+            .and_then(round_up)
+            .ok_or_else(too_large)?;
+        let cells = round_up(len.max(1)).ok_or_else(too_large)?;
+        let mapped = guard
+            .checked_mul(2)
+            .and_then(|size| size.checked_add(cells))
+            .ok_or_else(too_large)?;
+
+        let pages = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                mapped,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if pages == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        let tape = Self {
+            pages,
+            mapped,
+            cells: unsafe { pages.cast::<u8>().add(guard) },
+            len,
+        };
+        let protection = libc::PROT_READ | libc::PROT_WRITE;
+        if unsafe { libc::mprotect(tape.cells.cast(), cells, protection) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(tape)
+    }
+
+    pub(crate) fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.cells
+    }
+
+    /// The cell `ptr` points to, or `None` if it points outside the tape.
+    pub(crate) fn cell(&self, ptr: *const u8) -> Option<usize> {
+        Some((ptr as usize).wrapping_sub(self.cells as usize)).filter(|&cell| cell < self.len)
+    }
+}
+
+impl std::ops::Deref for GuardedTape {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.cells, self.len) }
+    }
+}
+
+impl std::ops::DerefMut for GuardedTape {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.cells, self.len) }
+    }
+}
+
+impl Drop for GuardedTape {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.pages, self.mapped) };
+    }
+}
+
 #[derive(Debug)]
 pub struct Function {
     buf: ExecutableBuffer,
     start: AssemblyOffset,
+    tape_size: usize,
+    // How far the code moves between two accesses to the tape, see `GuardedTape`.
+    reach: u64,
     // Keeps byte literals alive when generated code stores their raw pointers.
-    _literals: Vec<Box<[u8]>>,
+    literals: Vec<Box<[u8]>>,
+    relocations: Vec<Relocation>,
+    flush: Flush,
+    output_format: OutputFormat,
+    line_input: bool,
 }
 
 impl Function {
-    pub(super) fn new(
+    fn new(
         buf: ExecutableBuffer,
         start: AssemblyOffset,
+        tape_size: usize,
+        reach: u64,
         literals: Vec<Box<[u8]>>,
+        relocations: Vec<Relocation>,
     ) -> Self {
         Self {
             buf,
             start,
-            _literals: literals,
+            tape_size,
+            reach,
+            literals,
+            relocations,
+            flush: Flush::default(),
+            output_format: OutputFormat::default(),
+            line_input: false,
         }
     }
 
+    /// Maps previously generated code into executable memory, patching its relocations with
+    /// the addresses of this process' helpers and the given literals.
+    pub(crate) fn load(
+        code: &[u8],
+        start: usize,
+        tape_size: usize,
+        reach: u64,
+        literals: Vec<Box<[u8]>>,
+        relocations: Vec<Relocation>,
+    ) -> io::Result<Self> {
+        let invalid = |msg| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        if start > code.len() {
+            return Err(invalid("entry point outside the code"));
+        }
+
+        let mut buf = MutableBuffer::new(code.len())?;
+        buf.set_len(code.len());
+        buf.copy_from_slice(code);
+
+        for relocation in &relocations {
+            let address = match relocation.target {
+                Target::Helper(helper) => helper.address(),
+                Target::Literal(index) => literals
+                    .get(index)
+                    .ok_or_else(|| invalid("relocation to a missing literal"))?
+                    .as_ptr() as u64,
+            };
+
+            let end = relocation.offset.checked_add(super::RELOCATION_SIZE);
+            if end.is_none_or(|end| end > code.len()) {
+                return Err(invalid("relocation outside the code"));
+            }
+
+            super::relocate(&mut buf, relocation.offset, address);
+        }
+
+        cache_control::synchronize_icache(&buf);
+        let buf = buf.make_exec()?;
+        cache_control::prepare_for_execution(&buf);
+
+        Ok(Self::new(
+            buf,
+            AssemblyOffset(start),
+            tape_size,
+            reach,
+            literals,
+            relocations,
+        ))
+    }
+
+    /// The size of the generated machine code in bytes.
+    pub fn code_size(&self) -> usize {
+        self.buf.len()
+    }
+
+    pub(crate) fn code(&self) -> &[u8] {
+        &self.buf
+    }
+
+    pub(crate) fn start(&self) -> usize {
+        self.start.0
+    }
+
+    pub(crate) fn tape_size(&self) -> usize {
+        self.tape_size
+    }
+
+    pub(crate) fn reach(&self) -> u64 {
+        self.reach
+    }
+
+    pub(crate) fn literals(&self) -> &[Box<[u8]>] {
+        &self.literals
+    }
+
+    pub(crate) fn relocations(&self) -> &[Relocation] {
+        &self.relocations
+    }
+
+    /// Sets when output is written to stdout. Defaults to [`Flush::Exit`].
+    pub fn set_flush(mut self, flush: Flush) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Sets how written bytes are shown. Defaults to [`OutputFormat::Raw`].
+    pub fn set_output_format(mut self, format: OutputFormat) -> Self {
+        self.output_format = format;
+        self
+    }
+
+    /// Sets whether stdin is read one line at a time instead of in blocks, which leaves the
+    /// input after the current line to other readers of stdin, e.g. a REPL. Defaults to
+    /// `false`.
+    pub fn set_line_input(mut self, line_input: bool) -> Self {
+        self.line_input = line_input;
+        self
+    }
+
+    fn context(&self) -> Context {
+        Context::new(self.flush, self.output_format).set_line_input(self.line_input)
+    }
+
+    /// Runs the code on a fresh, zeroed tape.
+    ///
+    /// Panics if the data pointer ends outside the tape.
     pub fn run(&self) {
-        let fun: extern "C" fn() = unsafe { mem::transmute(self.buf.ptr(self.start)) };
-        (fun)();
+        let mut tape = self.guarded_tape(self.tape_size);
+        let mut context = self.context();
+        let end = unsafe { self.run_in(tape.as_mut_ptr(), &mut context) };
+
+        assert!(
+            tape.cell(end).is_some(),
+            "data pointer moved outside the tape"
+        );
     }
-}
 
-#[cfg(test)]
-impl Function {
-    pub(crate) fn literal_count(&self) -> usize {
-        self._literals.len()
+    /// Runs the code on `tape` with the data pointer starting at cell `start`, and returns the
+    /// cell the data pointer ended on.
+    ///
+    /// This lets Rust code fill the tape with data, run a program over it and inspect the
+    /// result afterwards. Code compiled without
+    /// [`assume_zeroed_tape(false)`](super::Jit::assume_zeroed_tape) may skip work that depends
+    /// on the cells being zero, so it should only be run on zeroed tapes. Programs optimized
+    /// without [`keep_final_tape`](crate::PassManager::keep_final_tape) may not leave their
+    /// cells behind.
+    ///
+    /// The program runs on a copy of `tape` between inaccessible memory, so a program that
+    /// accesses cells outside the tape is stopped by a segmentation fault instead of
+    /// overwriting other memory.
+    ///
+    /// Panics if `start` is outside the tape or the data pointer ends outside of it.
+    pub fn run_on(&self, tape: &mut [u8], start: usize) -> usize {
+        self.try_run_on(tape, start)
+            .expect("data pointer moved outside the tape")
+    }
+
+    /// Like [`run_on`](Function::run_on), but returns `None` instead of panicking if the data
+    /// pointer ends outside the tape. The cells are updated either way.
+    pub fn try_run_on(&self, tape: &mut [u8], start: usize) -> Option<usize> {
+        let mut context = self.context();
+        self.run_guarded(tape, start, &mut context)
+    }
+
+    /// Like [`try_run_on`](Function::try_run_on), but with output buffered in `context`.
+    pub(crate) fn run_guarded(
+        &self,
+        tape: &mut [u8],
+        start: usize,
+        context: &mut Context,
+    ) -> Option<usize> {
+        assert!(start < tape.len(), "start outside the tape");
+
+        let mut guarded = self.guarded_tape(tape.len());
+        guarded.copy_from_slice(tape);
+        let end = unsafe { self.run_in(guarded.as_mut_ptr().add(start), context) };
+        tape.copy_from_slice(&guarded);
+
+        guarded.cell(end)
+    }
+
+    fn guarded_tape(&self, len: usize) -> GuardedTape {
+        GuardedTape::new(len, self.reach).expect("could not map the tape")
+    }
+
+    /// Runs the code with the data pointer starting at `ptr` and returns the data pointer
+    /// after the program ends.
+    ///
+    /// Code compiled without [`assume_zeroed_tape(false)`](super::Jit::assume_zeroed_tape)
+    /// may skip work that depends on the cells being zero, so it should only be run on zeroed
+    /// tapes.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point into a tape that extends far enough in both directions for every cell
+    /// the program accesses.
+    pub unsafe fn run_from(&self, ptr: *mut u8) -> *mut u8 {
+        let mut context = self.context();
+        unsafe { self.run_in(ptr, &mut context) }
+    }
+
+    /// Like [`run_from`](Function::run_from), but with output buffered in `context` instead of
+    /// a context of its own.
+    ///
+    /// # Safety
+    ///
+    /// See [`run_from`](Function::run_from).
+    pub(crate) unsafe fn run_in(&self, ptr: *mut u8, context: &mut Context) -> *mut u8 {
+        let fun: extern "C" fn(*mut u8, &mut Context) -> *mut u8 =
+            unsafe { mem::transmute(self.buf.ptr(self.start)) };
+        (fun)(ptr, context)
     }
 }
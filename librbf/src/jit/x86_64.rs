@@ -1,28 +1,88 @@
 use super::Function;
-use super::common::{getchar, memzero, putbytes, putchar};
-use crate::ast::{Instruction::*, Program};
+use super::common::{BodyCells, CompileError, Helper, Relocation, Target, check_offsets};
+use crate::ast::{Instruction, Instruction::*, Program};
+use crate::cache::CacheKey;
+use crate::runtime::{
+    CONTEXT_INPUT, CONTEXT_INPUT_LIMIT, CONTEXT_INPUT_POS, CONTEXT_OUTPUT, CONTEXT_OUTPUT_LEN,
+    CONTEXT_OUTPUT_LIMIT, IoMode, numbers,
+};
 use dynasm::dynasm;
-use dynasmrt::{DynasmApi, DynasmLabelApi};
+use dynasmrt::x64::X64Relocation;
+use dynasmrt::{DynasmApi, DynasmLabelApi, VecAssembler};
+
+/// One code pointer for each procedure number a cell can hold.
+const PROCEDURE_TABLE_SIZE: usize = 256 * 8;
+
+/// Where the prologue stores the run context, in the stack slot that keeps the stack aligned.
+const CONTEXT_SLOT: i32 = -48;
+
+/// Callee-saved registers that hold cells a balanced loop only reads.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    Hoisted0 = 14,
+    Hoisted1 = 15,
+}
+
+impl From<Reg> for u8 {
+    fn from(reg: Reg) -> Self {
+        reg as u8
+    }
+}
+
+const HOISTED_REGISTERS: [Reg; 2] = [Reg::Hoisted0, Reg::Hoisted1];
+
+/// Strides of scans that compare a whole block of cells at once. They divide the block
+/// size, so every block holds the same lanes.
+const VECTOR_STRIDES: [u64; 4] = [1, 2, 4, 8];
+
+/// The encoding of `vpmovmskb eax, ymm0`.
+const VPMOVMSKB_EAX_YMM0: [u8; 4] = [0xC5, 0xFD, 0xD7, 0xC0];
+
+/// Version of the code this backend generates, part of every [`CacheKey`]. Bump it whenever
+/// a change makes the backend emit different code for the same program.
+const CODEGEN_VERSION: u32 = 2;
+
+/// Relocations patch the 64-bit immediate of a `mov r64, imm64`.
+pub(super) const RELOCATION_SIZE: usize = 8;
+
+/// Writes `address` into the relocation at `offset`.
+pub(super) fn relocate(code: &mut [u8], offset: usize, address: u64) {
+    code[offset..offset + RELOCATION_SIZE].copy_from_slice(&address.to_le_bytes());
+}
 
 /// Compiles brainfuck code and returns a `Function`.
 ///
 pub struct Jit {
     tape_size: usize,
-    ops: dynasmrt::x64::Assembler,
+    zeroed_tape: bool,
+    vectorize_scans: bool,
+    avx2: bool,
+    io_mode: IoMode,
+    ops: VecAssembler<X64Relocation>,
     start: dynasmrt::AssemblyOffset,
+    exit: dynasmrt::DynamicLabel,
     literals: Vec<Box<[u8]>>,
+    relocations: Vec<Relocation>,
+    hoisted: Vec<(i64, Reg)>,
 }
 
 impl Jit {
     /// Initializes a `Jit` with a tape size of `30_000`
     pub fn new() -> Jit {
-        let ops = dynasmrt::x64::Assembler::new().unwrap();
+        let mut ops = VecAssembler::new(0);
 
         Jit {
             tape_size: 30_000,
+            zeroed_tape: true,
+            vectorize_scans: true,
+            avx2: is_x86_feature_detected!("avx2"),
+            io_mode: IoMode::default(),
             start: ops.offset(),
+            exit: ops.new_dynamic_label(),
             ops,
             literals: Vec::new(),
+            relocations: Vec::new(),
+            hoisted: Vec::new(),
         }
     }
 
@@ -32,165 +92,781 @@ impl Jit {
         self
     }
 
+    /// Sets whether the generated code may assume that all cells are `0` when it starts.
+    /// Enabled by default, disable it for code that runs on a tape that already holds data.
+    pub fn assume_zeroed_tape(mut self, zeroed: bool) -> Self {
+        self.zeroed_tape = zeroed;
+        self
+    }
+
+    /// Sets whether scans with a stride of 1, 2, 4 or 8 compare a whole block of cells with
+    /// zero at once, using AVX2 if the CPU supports it and SSE2 otherwise. Enabled by
+    /// default.
+    pub fn vectorize_scans(mut self, vectorize: bool) -> Self {
+        self.vectorize_scans = vectorize;
+        self
+    }
+
+    /// Sets what `,` and `.` read and write, see [`IoMode`]. Defaults to bytes.
+    pub fn set_io_mode(mut self, mode: IoMode) -> Self {
+        self.io_mode = mode;
+        self
+    }
+
+    /// Uses SSE2 for vectorized scans even if the CPU supports AVX2.
+    #[cfg(test)]
+    pub(super) fn without_avx2(mut self) -> Self {
+        self.avx2 = false;
+        self
+    }
+
+    /// Returns a [`CacheKey`] for code compiled from `source` with these options.
+    pub fn cache_key(&self, source: &[u8]) -> CacheKey {
+        CacheKey::new(source)
+            .option("codegen", CODEGEN_VERSION)
+            .option("tape-size", self.tape_size)
+            .option("zeroed-tape", self.zeroed_tape)
+            .option("vectorize-scans", self.vectorize_scans)
+            .option("avx2", self.avx2)
+            .option("io-mode", format!("{:?}", self.io_mode))
+    }
+
     /// Generates machine code for the given program
-    pub fn compile(mut self, program: &Program) -> Function {
-        let frame_size = self.tape_size + 8;
+    ///
+    /// Fails if the program's offsets overflow or the code can't be assembled or mapped into
+    /// executable memory.
+    pub fn compile(mut self, program: &Program) -> Result<Function, CompileError> {
+        let reach = check_offsets(program)?;
 
-        // Prologue
+        // The tape is passed in rdi, the run context in rsi and the final data pointer returned
+        // in rax.
         dynasm!(self.ops
                 ; .arch x64
                 ; push rbp // Store frame pointer
                 ; mov rbp, rsp // Address of current stack frame
                 ; push rbx // Preserve callee-saved tape pointer register
-                ; sub rsp, frame_size as _ // Reserve memory for tape and keep stack aligned
-                ; lea rbx, [rsp] // Save memory address in rbx
+                ; push r12 // Preserve callee-saved procedure table register
+                ; push r13 // Preserve callee-saved storage register
+                ; push r14 // Preserve callee-saved registers for hoisted cells
+                ; push r15
+                ; sub rsp, (PROCEDURE_TABLE_SIZE + 8) as _ // Reserve procedure table and context slot
+                ; mov [rbp + CONTEXT_SLOT], rsi // Run context
+                ; mov rbx, rdi // Data pointer
+                ; mov r12, rsp // Procedure table
+                ; xor r13d, r13d // Storage register starts at 0
         );
 
-        // Zero tape
+        // Zero procedure table
         dynasm!(self.ops
                 ; .arch x64
-                ; mov rax, QWORD memzero as *const () as _
-                ; mov rdi, rbx
-                ; mov rsi, self.tape_size as _
-                ; call rax
+                ; mov rdi, r12
+                ; mov rsi, PROCEDURE_TABLE_SIZE as _
         );
+        self.call_helper(Helper::MemZero);
 
         self.generate(program);
 
-        // Epilogue
+        // Epilogue, also reached by `End` from inside procedures
+        let exit = self.exit;
         dynasm!(self.ops
                 ; .arch x64
-                ; add rsp, frame_size as _
+                ; =>exit
+                ; mov rax, rbx // Return the data pointer
+                ; lea rsp, [rbp - 40] // Drop the frame and any procedure return addresses
+                ; pop r15 // Restore callee-saved registers for hoisted cells
+                ; pop r14
+                ; pop r13 // Restore callee-saved storage register
+                ; pop r12 // Restore callee-saved procedure table register
                 ; pop rbx // Restore callee-saved tape pointer register
                 ; pop rbp // Restore frame pointer
                 ; ret
         );
 
-        let buf = self.ops.finalize().unwrap();
-        Function::new(buf, self.start, self.literals)
+        let code = self.ops.finalize().map_err(CompileError::Assemble)?;
+        Function::load(
+            &code,
+            self.start.0,
+            self.tape_size,
+            reach,
+            self.literals,
+            self.relocations,
+        )
+        .map_err(|e| CompileError::Memory(e.kind()))
+    }
+
+    /// Generates machine code for a single loop with the given body.
+    ///
+    /// The loop runs on a tape that already holds data, starting at the pointer passed to
+    /// [`Function::run_from`] and returning the data pointer once the loop is left. This lets
+    /// an interpreter hand hot loops over to native code.
+    pub fn compile_loop(self, body: &Program) -> Result<Function, CompileError> {
+        self.assume_zeroed_tape(false)
+            .compile(&vec![Loop(body.clone())])
     }
 
     fn generate(&mut self, program: &Program) {
         for ins in program.iter() {
-            match ins {
-                &Move(i) => {
-                    dynasm!(self.ops
+            self.instruction(0, ins);
+        }
+    }
+
+    /// Generates code for `ins` working on the cell at `offset` from the data pointer. Only
+    /// instructions that stay in place can have a non-zero offset.
+    fn instruction(&mut self, offset: i64, ins: &Instruction) {
+        match ins {
+            &Move(i) => self.move_tape(i),
+            &Add(i) => {
+                self.at_cell(offset, |jit, disp| {
+                    dynasm!(jit.ops
                             ; .arch x64
-                            ; add rbx, i as _
+                            ; add BYTE [rbx + disp], i as _
                     );
+                });
+            }
+            Write => {
+                match self.hoisted(offset) {
+                    Some(reg) => dynasm!(self.ops
+                            ; .arch x64
+                            ; movzx esi, Rb(reg)
+                    ),
+                    None => {
+                        self.at_cell(offset, |jit, disp| {
+                            dynasm!(jit.ops
+                                    ; .arch x64
+                                    ; movzx esi, BYTE [rbx + disp]
+                            );
+                        });
+                    }
                 }
-                &Add(i) => {
-                    dynasm!(self.ops
+                self.put_byte();
+            }
+            Read => {
+                self.get_byte();
+                self.at_cell(offset, |jit, disp| {
+                    dynasm!(jit.ops
                             ; .arch x64
-                            ; add BYTE [rbx], i as _
+                            ; mov [rbx + disp], al
                     );
+                });
+            }
+            &WriteConst(i) => {
+                let value = i as u8;
+                self.set(offset, value);
+
+                match self.io_mode {
+                    IoMode::Bytes => {
+                        dynasm!(self.ops
+                                ; .arch x64
+                                ; mov esi, value as _
+                        );
+                        self.put_byte();
+                    }
+                    IoMode::Numeric => self.put_bytes(&numbers(&[value])),
                 }
-                Write => {
-                    dynasm!(self.ops
+            }
+            WriteBytes(bytes) => {
+                // Writing no bytes leaves the cell alone.
+                if let Some(&last) = bytes.last() {
+                    self.set(offset, last);
+
+                    match self.io_mode {
+                        IoMode::Bytes => self.put_bytes(bytes),
+                        IoMode::Numeric => self.put_bytes(&numbers(bytes)),
+                    }
+                }
+            }
+            &Set(i) => self.set(offset, i as u8),
+            &Mul(dest, mul) => {
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; mov al, mul as _
+                );
+                match self.hoisted(offset) {
+                    Some(reg) => dynasm!(self.ops
                             ; .arch x64
-                            ; movzx rdi, [rbx]
-                            ; mov rax, QWORD putchar as *const () as _
-                            ; call rax
-                    );
+                            ; mul Rb(reg)
+                    ),
+                    None => {
+                        self.at_cell(offset, |jit, disp| {
+                            dynasm!(jit.ops
+                                    ; .arch x64
+                                    ; mul BYTE [rbx + disp]
+                            );
+                        });
+                    }
                 }
-                Read => {
-                    dynasm!(self.ops
+                self.at_cell(offset + dest, |jit, disp| {
+                    dynasm!(jit.ops
                             ; .arch x64
-                            ; mov rax, QWORD getchar as *const () as _
-                            ; call rax
-                            ; mov [rbx], al
+                            ; add [rbx + disp], al
                     );
+                });
+            }
+            MulRun(muls) => {
+                for &(dest, mul) in muls {
+                    self.at_cell(offset, |jit, disp| {
+                        dynasm!(jit.ops
+                                ; .arch x64
+                                ; mov al, mul as _
+                                ; mul BYTE [rbx + disp]
+                        );
+                    });
+                    self.at_cell(offset + dest, |jit, disp| {
+                        dynasm!(jit.ops
+                                ; .arch x64
+                                ; add [rbx + disp], al
+                        );
+                    });
                 }
-                &WriteConst(i) => {
-                    let value = i as u8;
+
+                self.set(offset, 0);
+            }
+            &Scan(i) => {
+                let rest_label = self.ops.new_dynamic_label();
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; cmp BYTE [rbx], 0
+                        ; je =>rest_label
+                );
+
+                if self.vectorize_scans && VECTOR_STRIDES.contains(&i.unsigned_abs()) {
+                    self.vector_scan(i);
+                } else {
+                    let move_label = self.ops.new_dynamic_label();
+
+                    match i32::try_from(i) {
+                        Ok(stride) => dynasm!(self.ops
+                                ; .arch x64
+                                ; =>move_label
+                                ; add rbx, stride
+                        ),
+                        Err(_) => dynasm!(self.ops
+                                ; .arch x64
+                                ; mov rdx, QWORD i
+                                ; =>move_label
+                                ; add rbx, rdx
+                        ),
+                    }
 
                     dynasm!(self.ops
                             ; .arch x64
-                            ; mov BYTE [rbx], value as _
-                            ; mov rdi, value as _
-                            ; mov rax, QWORD putchar as *const () as _
-                            ; call rax
+                            ; cmp BYTE [rbx], 0
+                            ; jne =>move_label
                     );
                 }
-                WriteBytes(bytes) => {
-                    let last = *bytes.last().unwrap();
-                    let (ptr, len) = self.retain_bytes(bytes);
 
-                    dynasm!(self.ops
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; =>rest_label
+                );
+            }
+            Loop(body) => {
+                let body_label = self.ops.new_dynamic_label();
+                let rest_label = self.ops.new_dynamic_label();
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; cmp BYTE [rbx], 0
+                        ; je =>rest_label
+                        ; =>body_label
+                );
+
+                self.generate(body);
+
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; cmp BYTE [rbx], 0
+                        ; jne =>body_label
+                        ; =>rest_label
+                );
+            }
+            BalancedLoop(body) => {
+                assert!(
+                    body.iter().all(|(_, ins)| ins.stays_in_place()),
+                    "balanced loop bodies can't move the data pointer"
+                );
+
+                let body_label = self.ops.new_dynamic_label();
+                let rest_label = self.ops.new_dynamic_label();
+
+                self.compare_zero(offset);
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; je =>rest_label
+                );
+
+                let hoisted = self.hoist(offset, body);
+
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; =>body_label
+                );
+
+                for (cell, ins) in body {
+                    self.instruction(offset + cell, ins);
+                }
+
+                self.compare_zero(offset);
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; jne =>body_label
+                        ; =>rest_label
+                );
+
+                self.hoisted.truncate(hoisted);
+            }
+            Procedure(body) => {
+                let body_label = self.ops.new_dynamic_label();
+                let rest_label = self.ops.new_dynamic_label();
+
+                // Register the body in the procedure table and skip over it
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; movzx eax, BYTE [rbx]
+                        ; lea rcx, [=>body_label]
+                        ; mov [r12 + rax * 8], rcx
+                        ; jmp =>rest_label
+                        ; =>body_label
+                        ; sub rsp, 8 // Realign the stack after the return address
+                );
+
+                self.generate(body);
+
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; add rsp, 8
+                        ; ret
+                        ; =>rest_label
+                );
+            }
+            Call => {
+                let rest_label = self.ops.new_dynamic_label();
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; movzx eax, BYTE [rbx]
+                        ; mov rax, [r12 + rax * 8]
+                        ; test rax, rax
+                        ; jz =>rest_label
+                        ; call rax
+                        ; =>rest_label
+                );
+            }
+            End => {
+                let exit = self.exit;
+                dynasm!(self.ops
+                        ; .arch x64
+                        ; jmp =>exit
+                );
+            }
+            Store => match self.hoisted(offset) {
+                Some(reg) => dynasm!(self.ops
+                        ; .arch x64
+                        ; mov r13b, Rb(reg)
+                ),
+                None => {
+                    self.at_cell(offset, |jit, disp| {
+                        dynasm!(jit.ops
+                                ; .arch x64
+                                ; mov r13b, [rbx + disp]
+                        );
+                    });
+                }
+            },
+            Load => {
+                self.at_cell(offset, |jit, disp| {
+                    dynasm!(jit.ops
                             ; .arch x64
-                            ; mov BYTE [rbx], last as _
-                            ; mov rdi, QWORD ptr as _
-                            ; mov rsi, len as _
-                            ; mov rax, QWORD putbytes as *const () as _
-                            ; call rax
+                            ; mov [rbx + disp], r13b
                     );
-                }
-                Set(i) => {
-                    dynasm!(self.ops
+                });
+            }
+            &Shift(i) if !(-7..8).contains(&i) => self.set(offset, 0),
+            &Shift(i) if i < 0 => {
+                self.at_cell(offset, |jit, disp| {
+                    dynasm!(jit.ops
                             ; .arch x64
-                            ; mov BYTE [rbx], (i % 0xFF) as _
+                            ; shr BYTE [rbx + disp], -i as _
                     );
-                }
-                &Mul(offset, mul) => {
-                    dynasm!(self.ops
+                });
+            }
+            &Shift(i) => {
+                self.at_cell(offset, |jit, disp| {
+                    dynasm!(jit.ops
                             ; .arch x64
-                            ; mov al, mul as _
-                            ; mul BYTE [rbx]
-                            ; add [rbx + offset as _], al
+                            ; shl BYTE [rbx + disp], i as _
                     );
-                }
-                MulRun(muls) => {
-                    for &(offset, mul) in muls {
-                        dynasm!(self.ops
-                                ; .arch x64
-                                ; mov al, mul as _
-                                ; mul BYTE [rbx]
-                                ; add [rbx + offset as _], al
-                        );
-                    }
-
-                    dynasm!(self.ops
+                });
+            }
+            Not => {
+                self.at_cell(offset, |jit, disp| {
+                    dynasm!(jit.ops
                             ; .arch x64
-                            ; mov BYTE [rbx], 0
+                            ; not BYTE [rbx + disp]
                     );
-                }
-                &Scan(i) => {
-                    let move_label = self.ops.new_dynamic_label();
-                    let rest_label = self.ops.new_dynamic_label();
-                    dynasm!(self.ops
+                });
+            }
+            And => {
+                self.at_cell(offset, |jit, disp| {
+                    dynasm!(jit.ops
                             ; .arch x64
-                            ; cmp BYTE [rbx], 0
-                            ; je =>rest_label
-                            ; =>move_label
-                            ; add rbx, i as _
-                            ; cmp BYTE [rbx], 0
-                            ; jne =>move_label
-                            ; =>rest_label
+                            ; and [rbx + disp], r13b
                     );
-                }
-                Loop(body) => {
-                    let body_label = self.ops.new_dynamic_label();
-                    let rest_label = self.ops.new_dynamic_label();
-                    dynasm!(self.ops
+                });
+            }
+            Or => {
+                self.at_cell(offset, |jit, disp| {
+                    dynasm!(jit.ops
                             ; .arch x64
-                            ; cmp BYTE [rbx], 0
-                            ; je =>rest_label
-                            ; =>body_label
+                            ; or [rbx + disp], r13b
                     );
+                });
+            }
+            Xor => {
+                self.at_cell(offset, |jit, disp| {
+                    dynasm!(jit.ops
+                            ; .arch x64
+                            ; xor [rbx + disp], r13b
+                    );
+                });
+            }
+        }
+    }
 
-                    self.generate(body);
+    /// Moves the data pointer by `amount` cells.
+    fn move_tape(&mut self, amount: i64) {
+        match i32::try_from(amount) {
+            Ok(amount) => dynasm!(self.ops
+                    ; .arch x64
+                    ; add rbx, amount
+            ),
+            Err(_) => dynasm!(self.ops
+                    ; .arch x64
+                    ; mov rdx, QWORD amount
+                    ; add rbx, rdx
+            ),
+        }
+    }
 
-                    dynasm!(self.ops
+    /// Generates code with `emit` that accesses the cell at `offset` as `[rbx + disp]`.
+    ///
+    /// If the offset doesn't fit in a 32-bit displacement, rbx points at the cell itself while
+    /// `emit`'s code runs, like the AArch64 backend computes addresses its loads can't encode.
+    /// The data pointer is kept in rdx meanwhile and restored without touching the flags.
+    fn at_cell(&mut self, offset: i64, emit: impl FnOnce(&mut Self, i32)) {
+        if let Ok(disp) = i32::try_from(offset) {
+            emit(self, disp);
+            return;
+        }
+
+        dynasm!(self.ops
+                ; .arch x64
+                ; mov rdx, rbx
+                ; mov rbx, QWORD offset
+                ; add rbx, rdx
+        );
+        emit(self, 0);
+        dynasm!(self.ops
+                ; .arch x64
+                ; mov rbx, rdx
+        );
+    }
+
+    /// Sets the cell at `offset` to `value`.
+    fn set(&mut self, offset: i64, value: u8) {
+        self.at_cell(offset, |jit, disp| {
+            dynasm!(jit.ops
+                    ; .arch x64
+                    ; mov BYTE [rbx + disp], value as i8
+            );
+        });
+    }
+
+    /// Generates a scan that compares aligned blocks of 16 or 32 cells with zero at once and
+    /// picks the first zero cell in the direction of the scan among the cells the stride
+    /// reaches. The current cell is known not to be zero.
+    ///
+    /// Aligned blocks never cross a page boundary, so the scan only touches pages that hold a
+    /// cell it would also reach one cell at a time. Cells before the data pointer in the
+    /// first block are masked out.
+    fn vector_scan(&mut self, stride: i64) {
+        let width: i32 = if self.avx2 { 32 } else { 16 };
+        let step = stride.unsigned_abs() as i32;
+        // One bit per cell of a block, set for the cells a stride starting at cell 0 reaches.
+        let lanes = (0..width)
+            .step_by(step as usize)
+            .fold(0u32, |lanes, cell| lanes | 1 << cell);
+        let all = u32::MAX >> (32 - width);
+        let block_label = self.ops.new_dynamic_label();
+        let found_label = self.ops.new_dynamic_label();
+
+        // edx holds the lanes of the cells the stride reaches from the data pointer.
+        dynasm!(self.ops
+                ; .arch x64
+                ; mov edx, lanes as i32
+                ; mov ecx, ebx
+                ; and ecx, step - 1
+                ; shl edx, cl
+                ; mov ecx, ebx
+                ; and ecx, width - 1
+                ; mov esi, all as i32
+        );
+
+        // esi masks out the cells of the first block that lie behind the data pointer.
+        if stride > 0 {
+            dynasm!(self.ops
+                    ; .arch x64
+                    ; shl esi, cl
+            );
+        } else {
+            dynasm!(self.ops
+                    ; .arch x64
+                    ; xor ecx, width - 1
+                    ; shr esi, cl
+            );
+        }
+
+        dynasm!(self.ops
+                ; .arch x64
+                ; and esi, edx
+                ; mov rdi, rbx
+                ; and rdi, -width
+        );
+        self.compare_block_zero();
+        dynasm!(self.ops
+                ; .arch x64
+                ; and eax, esi
+                ; jnz =>found_label
+                ; =>block_label
+        );
+
+        if stride > 0 {
+            dynasm!(self.ops
+                    ; .arch x64
+                    ; add rdi, width
+            );
+        } else {
+            dynasm!(self.ops
+                    ; .arch x64
+                    ; sub rdi, width
+            );
+        }
+
+        self.compare_block_zero();
+        dynasm!(self.ops
+                ; .arch x64
+                ; and eax, edx
+                ; jz =>block_label
+                ; =>found_label
+        );
+
+        if stride > 0 {
+            dynasm!(self.ops
+                    ; .arch x64
+                    ; bsf eax, eax
+            );
+        } else {
+            dynasm!(self.ops
+                    ; .arch x64
+                    ; bsr eax, eax
+            );
+        }
+
+        dynasm!(self.ops
+                ; .arch x64
+                ; lea rbx, [rdi + rax]
+        );
+
+        if self.avx2 {
+            // Avoids the penalty for mixing AVX and SSE code in the helpers.
+            dynasm!(self.ops
+                    ; .arch x64
+                    ; vzeroupper
+            );
+        }
+    }
+
+    /// Sets a bit in eax for each cell of the aligned block at rdi that is `0`.
+    fn compare_block_zero(&mut self) {
+        if self.avx2 {
+            dynasm!(self.ops
+                    ; .arch x64
+                    ; vpxor ymm0, ymm0, ymm0
+                    ; vpcmpeqb ymm0, ymm0, [rdi]
+                    // dynasm rejects the operands of `vpmovmskb eax, ymm0`
+                    ; .bytes VPMOVMSKB_EAX_YMM0
+            );
+        } else {
+            dynasm!(self.ops
+                    ; .arch x64
+                    ; pxor xmm0, xmm0
+                    ; pcmpeqb xmm0, [rdi]
+                    ; pmovmskb eax, xmm0
+            );
+        }
+    }
+
+    /// Loads the cells a balanced loop at `offset` only reads into free registers, and returns
+    /// the number of cells that were hoisted before.
+    fn hoist(&mut self, offset: i64, body: &[(i64, Instruction)]) -> usize {
+        let hoisted = self.hoisted.len();
+
+        for cell in BodyCells::of(body).invariant() {
+            let cell = offset + cell;
+            let free = HOISTED_REGISTERS
+                .into_iter()
+                .find(|&reg| self.hoisted.iter().all(|&(_, r)| r != reg));
+
+            if self.hoisted(cell).is_some() {
+                continue;
+            }
+
+            let Some(reg) = free else {
+                break;
+            };
+
+            self.at_cell(cell, |jit, disp| {
+                dynasm!(jit.ops
+                        ; .arch x64
+                        ; mov Rb(reg), [rbx + disp]
+                );
+            });
+            self.hoisted.push((cell, reg));
+        }
+
+        hoisted
+    }
+
+    /// The register holding the cell at `offset`, if it was hoisted.
+    fn hoisted(&self, offset: i64) -> Option<Reg> {
+        self.hoisted
+            .iter()
+            .find(|&&(cell, _)| cell == offset)
+            .map(|&(_, reg)| reg)
+    }
+
+    /// Sets the zero flag if the cell at `offset` is `0`.
+    fn compare_zero(&mut self, offset: i64) {
+        match self.hoisted(offset) {
+            Some(reg) => dynasm!(self.ops
+                    ; .arch x64
+                    ; test Rb(reg), Rb(reg)
+            ),
+            None => {
+                self.at_cell(offset, |jit, disp| {
+                    dynasm!(jit.ops
                             ; .arch x64
-                            ; cmp BYTE [rbx], 0
-                            ; jne =>body_label
-                            ; =>rest_label
+                            ; cmp BYTE [rbx + disp], 0
                     );
-                }
+                });
             }
         }
     }
 
+    /// Appends the byte in esi to the output buffer of the run context, or hands it to
+    /// `putchar` if the buffer is full or output has to be flushed. Numeric I/O formats it
+    /// with `putnumber` instead.
+    fn put_byte(&mut self) {
+        if self.io_mode == IoMode::Numeric {
+            dynasm!(self.ops
+                    ; .arch x64
+                    ; mov rdi, [rbp + CONTEXT_SLOT]
+            );
+            self.call_helper(Helper::PutNumber);
+            return;
+        }
+
+        let slow = self.ops.new_dynamic_label();
+        let done = self.ops.new_dynamic_label();
+
+        dynasm!(self.ops
+                ; .arch x64
+                ; mov rdi, [rbp + CONTEXT_SLOT]
+                ; mov rcx, [rdi + CONTEXT_OUTPUT_LEN as i32]
+                ; cmp rcx, [rdi + CONTEXT_OUTPUT_LIMIT as i32]
+                ; jae =>slow
+                ; mov rax, [rdi + CONTEXT_OUTPUT as i32]
+                ; mov [rax + rcx], sil
+                ; inc rcx
+                ; mov [rdi + CONTEXT_OUTPUT_LEN as i32], rcx
+                ; jmp =>done
+                ; =>slow
+        );
+        self.call_helper(Helper::PutChar);
+        dynasm!(self.ops
+                ; .arch x64
+                ; =>done
+        );
+    }
+
+    /// Takes the next byte from the input buffer of the run context into al, or gets it from
+    /// `getchar` if the buffer is used up or input is interactive. Numeric I/O reads a number
+    /// with `getnumber` instead.
+    fn get_byte(&mut self) {
+        dynasm!(self.ops
+                ; .arch x64
+                ; mov rdi, [rbp + CONTEXT_SLOT]
+        );
+
+        if self.io_mode == IoMode::Numeric {
+            self.call_helper(Helper::GetNumber);
+            return;
+        }
+
+        let slow = self.ops.new_dynamic_label();
+        let done = self.ops.new_dynamic_label();
+
+        dynasm!(self.ops
+                ; .arch x64
+                ; mov rcx, [rdi + CONTEXT_INPUT_POS as i32]
+                ; cmp rcx, [rdi + CONTEXT_INPUT_LIMIT as i32]
+                ; jae =>slow
+                ; mov rax, [rdi + CONTEXT_INPUT as i32]
+                ; movzx eax, BYTE [rax + rcx]
+                ; inc rcx
+                ; mov [rdi + CONTEXT_INPUT_POS as i32], rcx
+                ; jmp =>done
+                ; =>slow
+        );
+        self.call_helper(Helper::GetChar);
+        dynasm!(self.ops
+                ; .arch x64
+                ; =>done
+        );
+    }
+
+    fn put_bytes(&mut self, bytes: &[u8]) {
+        let (ptr, len) = self.retain_bytes(bytes);
+
+        dynasm!(self.ops
+                ; .arch x64
+                ; mov rdi, [rbp + CONTEXT_SLOT]
+                ; mov rsi, QWORD ptr as _
+        );
+        self.relocation(Target::Literal(self.literals.len() - 1));
+        dynasm!(self.ops
+                ; .arch x64
+                ; mov rdx, len as _
+        );
+        self.call_helper(Helper::PutBytes);
+    }
+
+    fn call_helper(&mut self, helper: Helper) {
+        dynasm!(self.ops
+                ; .arch x64
+                ; mov rax, QWORD helper.address() as _
+        );
+        self.relocation(Target::Helper(helper));
+        dynasm!(self.ops
+                ; .arch x64
+                ; call rax
+        );
+    }
+
+    /// Records that the `mov r64, imm64` emitted last holds the address of `target`.
+    fn relocation(&mut self, target: Target) {
+        let offset = self.ops.offset().0 - RELOCATION_SIZE;
+        self.relocations.push(Relocation { offset, target });
+    }
+
     fn retain_bytes(&mut self, bytes: &[u8]) -> (*const u8, usize) {
         let bytes = bytes.to_vec().into_boxed_slice();
         let ptr = bytes.as_ptr();
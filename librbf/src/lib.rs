@@ -6,11 +6,36 @@ extern crate combine;
 extern crate libc;
 
 mod ast;
+mod backend;
+mod cache;
+mod coverage;
+mod dialect;
+mod encoding;
+mod facts;
+mod interpreter;
 mod jit;
 mod opt;
 mod parser;
+mod partial_eval;
+mod reference;
+mod runtime;
+mod stats;
+mod tiered;
+mod verify;
 
 pub use ast::*;
-pub use jit::Jit;
-pub use opt::optimize;
-pub use parser::parse;
+pub use backend::{
+    Artifact, Backend, ExecutionOptions, JitBackend, Runnable, backends, find_backend,
+};
+pub use cache::{Cache, CacheKey};
+pub use coverage::{Coverage, Region};
+pub use dialect::{Dialect, DialectError};
+pub use encoding::{DecodeError, decode, encode};
+pub use jit::{CompileError, Function, Jit};
+pub use opt::{MAX_LEVEL, Pass, PassManager, UnknownPass, optimize};
+pub use parser::{Extensions, parse, parse_with_spans};
+pub use reference::{Execution, Reference, ReferenceError};
+pub use runtime::{Flush, IoMode, OutputFormat};
+pub use stats::{LoopReason, LoopStats, Stats};
+pub use tiered::Tiered;
+pub use verify::{Engine, Verifier, VerifyError, verify};
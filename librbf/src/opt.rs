@@ -1,73 +1,614 @@
+use std::collections::HashSet;
+use std::fmt;
+use std::str::FromStr;
+
+use crate::facts::CellFacts;
+use crate::jit::common::BodyCells;
+use crate::partial_eval::partial_eval;
 use crate::{Instruction, Program, ast::Instruction::*};
 
+/// Version of the optimizer's output, part of every [`CacheKey`](crate::CacheKey). Bump it
+/// whenever a change makes the optimizer produce different programs.
+pub(crate) const VERSION: u32 = 9;
+
+/// The highest optimization level, used by [`optimize`].
+pub const MAX_LEVEL: u8 = 3;
+
+/// Optimizes a program with all passes of the highest optimization level.
 pub fn optimize(program: Program) -> Program {
-    optimize_program(program)
+    PassManager::with_level(MAX_LEVEL).run(program)
+}
+
+/// A named optimization pass.
+///
+/// Each pass rewrites the program, including the bodies of loops and procedures, and can be
+/// enabled or disabled on its own in a [`PassManager`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Pass {
+    /// Merges runs of adds, moves and shifts, and removes those that cancel out:
+    /// `++-` becomes `Add(1)`.
+    Fold,
+    /// Removes code that can't run or has no effect: everything after `End`, and loops and
+    /// transfers on a cell that was just cleared.
+    DeadCode,
+    /// Turns loops that count the current cell to zero in odd steps into `Set(0)`: `[-]`,
+    /// `[+]` or `[---]`.
+    ClearLoop,
+    /// Turns loops that only move the data pointer into `Scan`: `[>>]`.
+    Scan,
+    /// Turns transfer loops into a single `MulRun`: `[>++<-]` or `[--->+<]`.
+    MulRun,
+    /// Folds known cell values through later operations: `[-]+++` becomes `Set(3)`.
+    ConstFold,
+    /// Removes stores to cells that are overwritten before anything reads them, even across
+    /// moves: `+>+<[-]` becomes `Move(1)`, `Add(1)`, `Move(-1)`, `Set(0)`. Loops, scans and
+    /// calls may read any cell, so stores before them stay.
+    DeadStore,
+    /// Turns writes of known values into `WriteConst` and merges adjacent constant writes into
+    /// `WriteBytes`.
+    WriteFold,
+    /// Tracks the cell values known from the start of the program through the whole program:
+    /// it removes loops on cells known to be zero, turns `Add`s and writes of known cells into
+    /// `Set` and `WriteConst`, and replaces transfers from known cells with adds. `,>[-]<[>+<-]`
+    /// becomes `Read`, `MulRun(vec![(1, 1)])`, and `[comment]+.` becomes `Set(1)`,
+    /// `WriteConst(1)`.
+    ///
+    /// Unless the [`PassManager`] assumes a zeroed tape, no cell is known at the start.
+    ConstProp,
+    /// Runs the start of the program up to the first input at compile time and replaces it
+    /// with the output and the cells it produced: `++++++++[>++++++++<-]>+.` becomes
+    /// `WriteConst(65)`.
+    ///
+    /// This assumes the program starts on a zeroed tape, so a [`PassManager`] only runs it
+    /// with [`assume_zeroed_tape`](PassManager::assume_zeroed_tape) enabled.
+    PartialEval,
+    /// Turns loops that return the data pointer to the loop's cell into `BalancedLoop`, whose
+    /// body addresses cells by offset: `[>.<-]` becomes
+    /// `BalancedLoop(vec![(1, Write), (0, Add(-1))])`.
+    ///
+    /// The other passes don't look into balanced loop bodies, so this pass always runs last,
+    /// once the others are done.
+    BalancedLoop,
+}
+
+/// Runs a sequence of [`Pass`]es over a program until none of them changes it anymore.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PassManager {
+    passes: Vec<Pass>,
+    zeroed_tape: bool,
+    final_tape: bool,
+}
+
+/// A pass name that doesn't belong to any [`Pass`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnknownPass(pub String);
+
+impl Pass {
+    /// All passes, in the order optimization levels run them.
+    pub const ALL: [Pass; 11] = [
+        Pass::Fold,
+        Pass::DeadCode,
+        Pass::ClearLoop,
+        Pass::Scan,
+        Pass::MulRun,
+        Pass::ConstFold,
+        Pass::DeadStore,
+        Pass::WriteFold,
+        Pass::ConstProp,
+        Pass::PartialEval,
+        Pass::BalancedLoop,
+    ];
+
+    /// The name used for this pass on the command line, e.g. `mul-run`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Pass::Fold => "fold",
+            Pass::DeadCode => "dead-code",
+            Pass::ClearLoop => "clear-loop",
+            Pass::Scan => "scan",
+            Pass::MulRun => "mul-run",
+            Pass::ConstFold => "const-fold",
+            Pass::DeadStore => "dead-store",
+            Pass::WriteFold => "write-fold",
+            Pass::ConstProp => "const-prop",
+            Pass::PartialEval => "partial-eval",
+            Pass::BalancedLoop => "balanced-loop",
+        }
+    }
+
+    /// The lowest optimization level that runs this pass.
+    pub fn level(self) -> u8 {
+        match self {
+            Pass::Fold | Pass::DeadCode => 1,
+            Pass::ClearLoop | Pass::Scan | Pass::MulRun | Pass::ConstFold | Pass::DeadStore => 2,
+            Pass::WriteFold | Pass::ConstProp | Pass::PartialEval | Pass::BalancedLoop => 3,
+        }
+    }
+
+    /// Runs this pass once over `program`, which starts on a zeroed tape.
+    pub fn run(self, program: Program) -> Program {
+        self.run_on(program, true, false)
+    }
+
+    /// Runs this pass once over `program`, which starts on a zeroed tape if `zeroed_tape` is
+    /// set and leaves the tape as the unoptimized program would if `final_tape` is set.
+    fn run_on(self, program: Program, zeroed_tape: bool, final_tape: bool) -> Program {
+        let push = match self {
+            Pass::Fold => fold,
+            Pass::DeadCode => dead_code,
+            Pass::ClearLoop => clear_loop,
+            Pass::Scan => scan,
+            Pass::MulRun => mul_run,
+            Pass::ConstFold => const_fold,
+            Pass::WriteFold => write_fold,
+            Pass::DeadStore => return dead_store(program),
+            Pass::ConstProp => return const_prop(program, zeroed_tape),
+            Pass::PartialEval if zeroed_tape => return partial_eval(program, final_tape),
+            Pass::PartialEval => return program,
+            Pass::BalancedLoop => balanced_loop,
+        };
+
+        rewrite(program, push)
+    }
+}
+
+impl fmt::Display for Pass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for Pass {
+    type Err = UnknownPass;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Pass::ALL
+            .into_iter()
+            .find(|pass| pass.name() == name)
+            .ok_or_else(|| UnknownPass(name.to_string()))
+    }
+}
+
+impl fmt::Display for UnknownPass {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = Pass::ALL.iter().map(|pass| pass.name()).collect();
+        write!(
+            f,
+            "unknown pass `{}`, expected one of: {}",
+            self.0,
+            names.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownPass {}
+
+impl Default for PassManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PassManager {
+    /// Creates a `PassManager` without any passes, which leaves programs unchanged.
+    pub fn new() -> PassManager {
+        PassManager {
+            passes: Vec::new(),
+            zeroed_tape: true,
+            final_tape: false,
+        }
+    }
+
+    /// Creates a `PassManager` with the passes of an optimization level from `0`, which runs
+    /// no passes, to [`MAX_LEVEL`].
+    pub fn with_level(level: u8) -> PassManager {
+        PassManager {
+            passes: Pass::ALL
+                .into_iter()
+                .filter(|pass| pass.level() <= level)
+                .collect(),
+            zeroed_tape: true,
+            final_tape: false,
+        }
+    }
+
+    /// Appends a pass. Passes run in the order they were added, except for
+    /// [`Pass::BalancedLoop`], which always runs last.
+    pub fn add_pass(mut self, pass: Pass) -> Self {
+        self.passes.push(pass);
+        self
+    }
+
+    /// Removes a pass.
+    pub fn disable_pass(mut self, pass: Pass) -> Self {
+        self.passes.retain(|&p| p != pass);
+        self
+    }
+
+    /// Sets whether programs start on a zeroed tape. Enabled by default, disable it for
+    /// programs that run on a tape that already holds data, which skips
+    /// [`Pass::PartialEval`] and starts [`Pass::ConstProp`] without any known cells.
+    pub fn assume_zeroed_tape(mut self, zeroed: bool) -> Self {
+        self.zeroed_tape = zeroed;
+        self
+    }
+
+    /// Sets whether optimized programs leave the same cells behind as the original. Disabled
+    /// by default, which lets [`Pass::PartialEval`] drop the cells of a program that ends
+    /// without reading input. Enable it when the tape is inspected after the run, e.g. with
+    /// [`Function::run_on`](crate::Function::run_on).
+    pub fn keep_final_tape(mut self, keep: bool) -> Self {
+        self.final_tape = keep;
+        self
+    }
+
+    /// The passes that will run, in order.
+    pub fn passes(&self) -> &[Pass] {
+        &self.passes
+    }
+
+    /// Runs all passes in order, repeating them until the program doesn't change anymore.
+    /// [`Pass::BalancedLoop`] runs once afterwards.
+    pub fn run(&self, mut program: Program) -> Program {
+        let (last, passes): (Vec<Pass>, Vec<Pass>) = self
+            .passes
+            .iter()
+            .partition(|&&pass| pass == Pass::BalancedLoop);
+
+        while !passes.is_empty() {
+            let optimized = passes.iter().fold(program.clone(), |program, pass| {
+                pass.run_on(program, self.zeroed_tape, self.final_tape)
+            });
+
+            if optimized == program {
+                break;
+            }
+
+            program = optimized;
+        }
+
+        last.iter().fold(program, |program, pass| {
+            pass.run_on(program, self.zeroed_tape, self.final_tape)
+        })
+    }
 }
 
-fn optimize_program(program: Program) -> Program {
+/// Rebuilds `program` by pushing each instruction with `push`, which may combine it with the
+/// instructions before it. Loop and procedure bodies are rewritten first.
+fn rewrite(program: Program, push: fn(&mut Program, Instruction)) -> Program {
     let mut out = Vec::with_capacity(program.len());
 
     for ins in program {
-        optimize_instruction(&mut out, ins);
+        let ins = match ins {
+            Loop(body) => Loop(rewrite(body, push)),
+            Procedure(body) => Procedure(rewrite(body, push)),
+            ins => ins,
+        };
+
+        push(&mut out, ins);
     }
 
     out
 }
 
-fn optimize_instruction(out: &mut Program, ins: Instruction) {
+fn fold(out: &mut Program, ins: Instruction) {
+    if let Add(0) | Move(0) | Shift(0) = ins {
+        return;
+    }
+
+    match (out.pop(), ins) {
+        (Some(Add(a)), Add(b)) => fold(out, Add(a + b)),
+        (Some(Move(a)), Move(b)) => fold(out, Move(a + b)),
+        (Some(Shift(a)), Shift(b)) if a.signum() == b.signum() => fold(out, Shift(a + b)),
+        (Some(Not), Not) => (),
+        (prev, ins) => {
+            out.extend(prev);
+            out.push(ins);
+        }
+    }
+}
+
+fn dead_code(out: &mut Program, ins: Instruction) {
+    match (out.last(), ins) {
+        // Nothing after `End` can run.
+        (Some(End), _) => (),
+        (Some(Set(0)), Loop(_) | BalancedLoop(_) | Mul(..) | MulRun(_)) => (),
+        (_, ins) => out.push(ins),
+    }
+}
+
+fn clear_loop(out: &mut Program, ins: Instruction) {
     match ins {
-        Add(0) | Move(0) => (),
-        Loop(body) => {
-            optimize_non_loop(out, optimize_loop(optimize_program(body)));
+        // With wrapping cells, any odd step reaches zero.
+        Loop(body) => match body[..] {
+            [Add(n)] if n % 2 != 0 => out.push(Set(0)),
+            [Set(0)] => out.push(Set(0)),
+            _ => out.push(Loop(body)),
+        },
+        ins => out.push(ins),
+    }
+}
+
+fn scan(out: &mut Program, ins: Instruction) {
+    match ins {
+        Loop(body) => match body[..] {
+            [Move(n)] => out.push(Scan(n)),
+            _ => out.push(Loop(body)),
+        },
+        ins => out.push(ins),
+    }
+}
+
+fn mul_run(out: &mut Program, ins: Instruction) {
+    match ins {
+        Loop(body) if !body.is_empty() => out.push(optimize_mul(body)),
+        ins => out.push(ins),
+    }
+}
+
+fn const_fold(out: &mut Program, ins: Instruction) {
+    let ins = match ins {
+        Shift(n) if !(-7..8).contains(&n) => Set(0),
+        ins => ins,
+    };
+
+    match (out.pop(), ins) {
+        (Some(Set(a)), Add(b)) => const_fold(out, Set(a + b)),
+        (Some(Add(_) | Set(_)), Set(n)) => const_fold(out, Set(n)),
+        (Some(Add(_) | Set(_)), Load) => const_fold(out, Load),
+        (Some(Set(a)), Shift(b)) => const_fold(out, Set(shift(a as u8, b) as i64)),
+        (Some(Set(n)), Not) => const_fold(out, Set(!(n as u8) as i64)),
+        // After `Store` the cell and the storage register hold the same value.
+        (Some(Store), Load | And | Or) => out.push(Store),
+        (Some(Store), Xor) => {
+            out.push(Store);
+            const_fold(out, Set(0));
+        }
+        (prev, ins) => {
+            out.extend(prev);
+            out.push(ins);
         }
-        ins => optimize_non_loop(out, ins),
     }
 }
 
-fn optimize_non_loop(out: &mut Program, ins: Instruction) {
-    let Some(prev) = out.pop() else {
+/// Removes stores that are overwritten before they are read, walking `program` backwards.
+/// Loop and procedure bodies are rewritten on their own.
+fn dead_store(program: Program) -> Program {
+    let mut out = Vec::with_capacity(program.len());
+    // Cells that are overwritten before the next read, by offset from the data pointer at the
+    // end of the program. Everything is live at the end, the tape may be used afterwards.
+    let mut dead = HashSet::new();
+    let mut ptr = 0;
+
+    for ins in program.into_iter().rev() {
+        let ins = match ins {
+            Loop(body) => Loop(dead_store(body)),
+            Procedure(body) => Procedure(dead_store(body)),
+            ins => ins,
+        };
+
+        match ins {
+            Move(n) => ptr -= n,
+            Add(_) | Set(_) | Shift(_) | Not | And | Or | Xor | Load if dead.contains(&ptr) => {
+                continue;
+            }
+            Mul(offset, _) if dead.contains(&(ptr + offset)) => continue,
+            MulRun(ref muls)
+                if dead.contains(&ptr)
+                    && muls
+                        .iter()
+                        .all(|(offset, _)| dead.contains(&(ptr + offset))) =>
+            {
+                continue;
+            }
+            Set(_) | Read | Load | WriteConst(_) | WriteBytes(_) => {
+                dead.insert(ptr);
+            }
+            Add(_) | Write | Store | Shift(_) | Not | And | Or | Xor => {
+                dead.remove(&ptr);
+            }
+            Mul(offset, _) => {
+                dead.remove(&ptr);
+                dead.remove(&(ptr + offset));
+            }
+            MulRun(ref muls) => {
+                dead.remove(&ptr);
+
+                for (offset, _) in muls {
+                    dead.remove(&(ptr + offset));
+                }
+            }
+            Scan(_) | Loop(_) | BalancedLoop(_) | Procedure(_) | Call | End => dead.clear(),
+        }
+
         out.push(ins);
-        return;
-    };
+    }
+
+    out.reverse();
+    out
+}
 
-    match (prev, ins) {
-        (Add(a), Add(b)) => optimize_instruction(out, Add(a + b)),
-        (Move(a), Move(b)) => optimize_instruction(out, Move(a + b)),
-        (Set(a), Add(b)) => optimize_non_loop(out, Set(a + b)),
-        (Add(_), Set(n)) | (Set(_), Set(n)) => optimize_non_loop(out, Set(n)),
-        (Set(0), Loop(_)) | (Set(0), Mul(_, _)) | (Set(0), MulRun(_)) => out.push(Set(0)),
-        (Set(n), Write) => optimize_non_loop(out, WriteConst(n)),
-        (WriteConst(a), WriteConst(b)) => {
-            optimize_non_loop(out, WriteBytes(vec![byte(a), byte(b)]))
-        }
-        (WriteBytes(mut bytes), WriteConst(n)) => {
+fn write_fold(out: &mut Program, ins: Instruction) {
+    match (out.pop(), ins) {
+        (Some(Set(n)), Write) => write_fold(out, WriteConst(n)),
+        // Constant writes leave the written byte in the cell.
+        (Some(Add(_) | Set(_)), ins @ (WriteConst(_) | WriteBytes(_))) => write_fold(out, ins),
+        (Some(WriteConst(a)), WriteConst(b)) => write_fold(out, WriteBytes(vec![byte(a), byte(b)])),
+        (Some(WriteBytes(mut bytes)), WriteConst(n)) => {
             bytes.push(byte(n));
-            optimize_non_loop(out, WriteBytes(bytes));
+            write_fold(out, WriteBytes(bytes));
         }
-        (WriteBytes(mut bytes), WriteBytes(mut more)) => {
+        (Some(WriteBytes(mut bytes)), WriteBytes(mut more)) => {
             bytes.append(&mut more);
-            optimize_non_loop(out, WriteBytes(bytes));
+            write_fold(out, WriteBytes(bytes));
         }
-        (WriteConst(n), WriteBytes(mut bytes)) => {
+        (Some(WriteConst(n)), WriteBytes(mut bytes)) => {
             bytes.insert(0, byte(n));
-            optimize_non_loop(out, WriteBytes(bytes));
+            write_fold(out, WriteBytes(bytes));
         }
-
         (prev, ins) => {
-            out.push(prev);
+            out.extend(prev);
             out.push(ins);
         }
     }
 }
 
-fn optimize_loop(program: Program) -> Instruction {
-    match &program[..] {
-        [] => Loop(program),
-        [Add(-1)] => Set(0),
-        [Move(n)] => Scan(*n),
-        [Set(0)] => Set(0),
-        _ => optimize_mul(program),
+/// Rewrites `program` with the cell values known before each instruction. Loop and procedure
+/// bodies start without any known cells.
+fn const_prop(program: Program, zeroed_tape: bool) -> Program {
+    let mut facts = CellFacts::new(zeroed_tape);
+    let mut out = Vec::with_capacity(program.len());
+
+    for ins in program {
+        propagate(&mut out, &mut facts, ins);
     }
+
+    out
+}
+
+/// Pushes `ins`, or what it does given the known cell values, and updates them. The facts
+/// are based at the current cell.
+fn propagate(out: &mut Program, facts: &mut CellFacts, ins: Instruction) {
+    let known = facts.known(0);
+
+    match ins {
+        Move(n) => {
+            facts.rebase(n);
+            out.push(Move(n));
+        }
+        Add(n) => match known {
+            Some(value) => propagate(out, facts, Set(value.wrapping_add(n as u8) as i64)),
+            None => {
+                facts.set_unknown(0);
+                out.push(Add(n));
+            }
+        },
+        Set(n) => {
+            if known != Some(n as u8) {
+                facts.set_known(0, n as u8);
+                out.push(Set(n));
+            }
+        }
+        Mul(offset, factor) => match known {
+            Some(value) => add_at(out, facts, offset, value.wrapping_mul(factor as u8)),
+            None => {
+                facts.set_unknown(offset);
+                out.push(Mul(offset, factor));
+            }
+        },
+        MulRun(muls) => match known {
+            Some(0) => (),
+            Some(value) => {
+                for (offset, factor) in muls {
+                    add_at(out, facts, offset, value.wrapping_mul(factor as u8));
+                }
+
+                propagate(out, facts, Set(0));
+            }
+            None => {
+                for &(offset, _) in &muls {
+                    facts.set_unknown(offset);
+                }
+
+                facts.set_known(0, 0);
+                out.push(MulRun(muls));
+            }
+        },
+        Write => match known {
+            Some(value) => out.push(WriteConst(value as i64)),
+            None => out.push(Write),
+        },
+        WriteConst(n) => {
+            facts.set_known(0, n as u8);
+            out.push(WriteConst(n));
+        }
+        WriteBytes(bytes) => {
+            if let Some(&last) = bytes.last() {
+                facts.set_known(0, last);
+            }
+
+            out.push(WriteBytes(bytes));
+        }
+        Shift(n) => match known {
+            Some(value) => propagate(out, facts, Set(shift(value, n) as i64)),
+            None => {
+                facts.set_unknown(0);
+                out.push(Shift(n));
+            }
+        },
+        Not => match known {
+            Some(value) => propagate(out, facts, Set(!value as i64)),
+            None => {
+                facts.set_unknown(0);
+                out.push(Not);
+            }
+        },
+        Read | Load | And | Or | Xor => {
+            facts.set_unknown(0);
+            out.push(ins);
+        }
+        Scan(_) | Loop(_) | BalancedLoop(_) if known == Some(0) => (),
+        Scan(n) => {
+            facts.reset_to_current_zero();
+            out.push(Scan(n));
+        }
+        Loop(body) => {
+            facts.reset_to_current_zero();
+            out.push(Loop(const_prop(body, false)));
+        }
+        BalancedLoop(body) => {
+            // The data pointer stays put, so only the cells written in the body change.
+            for cell in BodyCells::of(&body).writes {
+                facts.set_unknown(cell);
+            }
+
+            facts.set_known(0, 0);
+            out.push(BalancedLoop(body));
+        }
+        Procedure(body) => out.push(Procedure(const_prop(body, false))),
+        Call => {
+            // The procedure may move the data pointer and change any cell.
+            facts.forget_all();
+            out.push(Call);
+        }
+        Store | End => out.push(ins),
+    }
+}
+
+/// Adds `delta` to the cell at `offset`.
+fn add_at(out: &mut Program, facts: &mut CellFacts, offset: i64, delta: u8) {
+    if delta != 0 {
+        propagate(out, facts, Move(offset));
+        propagate(out, facts, Add(delta as i8 as i64));
+        propagate(out, facts, Move(-offset));
+    }
+}
+
+fn balanced_loop(out: &mut Program, ins: Instruction) {
+    match ins {
+        Loop(body) if !body.is_empty() => out.push(balance(body)),
+        ins => out.push(ins),
+    }
+}
+
+/// Rewrites a loop body that returns to the loop's cell with offsets instead of moves.
+fn balance(program: Program) -> Instruction {
+    let mut body = Vec::with_capacity(program.len());
+    let mut offset = 0;
+
+    for ins in program.iter() {
+        match ins {
+            Move(i) => offset += i,
+            ins if ins.stays_in_place() => body.push((offset, ins.clone())),
+            _ => return Loop(program),
+        }
+    }
+
+    if offset != 0 {
+        return Loop(program);
+    }
+
+    BalancedLoop(body)
 }
 
 fn optimize_mul(program: Program) -> Instruction {
@@ -83,17 +624,27 @@ fn optimize_mul(program: Program) -> Instruction {
         }
     }
 
-    let source = muls
+    let step = muls
         .iter()
         .find_map(|&(offset, factor)| (offset == 0).then_some(factor));
 
-    if !is_mul || offset != 0 || source != Some(-1) {
+    // Only odd steps are guaranteed to reach zero, even ones may skip it and loop forever.
+    let Some(step) = step.filter(|step| step % 2 != 0) else {
+        return Loop(program);
+    };
+
+    if !is_mul || offset != 0 {
         return Loop(program);
     }
 
+    // Starting at `cell`, the loop runs `k` times until `cell + k * step` wraps to zero, so
+    // `k = -cell * step⁻¹ (mod 256)` and each transfer adds `cell * factor * -step⁻¹`.
+    let scale = inverse(step as u8).wrapping_neg();
     let mut transfers: Vec<_> = muls
         .into_iter()
         .filter(|&(offset, _)| offset != 0)
+        .map(|(offset, factor)| (offset, (factor as u8).wrapping_mul(scale) as i8 as i64))
+        .filter(|&(_, factor)| factor != 0)
         .collect();
     transfers.sort_by_key(|&(offset, _)| offset);
 
@@ -112,6 +663,26 @@ fn add_mul(muls: &mut Vec<(i64, i64)>, offset: i64, amount: i64) {
     }
 }
 
+/// The multiplicative inverse of an odd number modulo 256.
+fn inverse(n: u8) -> u8 {
+    // Each Newton step doubles the number of correct low bits, starting with three.
+    let mut inverse = n;
+
+    for _ in 0..2 {
+        inverse = inverse.wrapping_mul(2u8.wrapping_sub(n.wrapping_mul(inverse)));
+    }
+
+    inverse
+}
+
+fn shift(value: u8, amount: i64) -> u8 {
+    match amount {
+        0..8 => value << amount,
+        -7..0 => value >> -amount,
+        _ => 0,
+    }
+}
+
 fn byte(i: i64) -> u8 {
-    (i % 0xFF) as u8
+    i as u8
 }
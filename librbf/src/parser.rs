@@ -5,16 +5,56 @@ use crate::ast::{Instruction::*, *};
 use combine::{
     Parser, Stream, between,
     byte::byte,
-    choice, eof, many, many1, satisfy, skip_many,
+    choice, eof, many, many1, position, satisfy, skip_many,
     stream::{ReadStream, buffered::BufferedStream, state::State},
 };
 
+/// Optional language extensions recognized by the parser.
+///
+/// Without extensions, only the eight Brainfuck commands are parsed and every other byte is a
+/// comment.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Extensions {
+    /// [pbrain](https://esolangs.org/wiki/Pbrain) procedures: `(` and `)` enclose the
+    /// definition of the procedure numbered by the current cell, `:` calls the procedure
+    /// numbered by the current cell.
+    pub procedures: bool,
+    /// [Extended Brainfuck Type I](https://esolangs.org/wiki/Extended_Brainfuck): `@` ends the
+    /// program, `$` and `!` copy the current cell to and from the storage register, `{` and `}`
+    /// shift the current cell left and right, `~` inverts it and `&`, `|` and `^` combine it
+    /// with the storage register.
+    pub extended: bool,
+}
+
+impl Extensions {
+    /// Whether `c` is a command with these extensions enabled.
+    fn is_command(self, c: u8) -> bool {
+        b"+-><,.[]".contains(&c)
+            || (self.procedures && b"():".contains(&c))
+            || (self.extended && b"@$!{}~&|^".contains(&c))
+    }
+
+    /// Parses source with these extensions and returns a [Program](type.Program.html).
+    pub fn parse<R: Read>(self, input: R) -> Program {
+        self.parse_with_spans(input).0
+    }
+
+    /// Parses source with these extensions and returns a [Program](type.Program.html) together
+    /// with the [SourceMap](type.SourceMap.html) locating each instruction in the source.
+    pub fn parse_with_spans<R: Read>(self, input: R) -> (Program, SourceMap) {
+        let stream = BufferedStream::new(State::new(ReadStream::new(input)), 1);
+        let ((parsed, _eof), _state) = (program(self), eof()).parse(stream).unwrap();
+        parsed
+    }
+}
+
 parser! {
     #[inline(always)]
-    fn program[I]()(I) -> Program
-        where [I: Stream<Item=u8>]
+    fn program[I](extensions: Extensions)(I) -> (Program, SourceMap)
+        where [I: Stream<Item=u8, Position=usize>]
     {
-        let comments = || skip_many(satisfy(|c| !"+-><,.[]".bytes().any(|t| t == c)));
+        let extensions = *extensions;
+        let comments = || skip_many(satisfy(move |c| !extensions.is_command(c)));
         let chars = |c| many1::<Vec<_>, _>(byte(c));
 
         let add = chars(b'+').map(|s: _| Add(s.len() as i64));
@@ -23,29 +63,60 @@ parser! {
         let right = chars(b'>').map(|s: _| Move(s.len() as i64));
         let read = byte(b',').map(|_| Read);
         let write = byte(b'.').map(|_| Write);
+        // Extension commands are skipped as comments unless they are enabled.
+        let call = byte(b':').map(|_| Call);
+        let end = byte(b'@').map(|_| End);
+        let store = byte(b'$').map(|_| Store);
+        let load = byte(b'!').map(|_| Load);
+        let shift_left = chars(b'{').map(|s: _| Shift(s.len() as i64));
+        let shift_right = chars(b'}').map(|s: _| Shift(-(s.len() as i64)));
+        let not = byte(b'~').map(|_| Not);
+        let and = byte(b'&').map(|_| And);
+        let or = byte(b'|').map(|_| Or);
+        let xor = byte(b'^').map(|_| Xor);
 
-        let bfloop = between(byte(b'['), byte(b']'), program()).map(Loop);
-
-        let instruction = choice((
+        let simple = choice((
             add,
             sub,
             left,
             right,
             read,
             write,
-            bfloop
-        )).skip(comments());
+            call,
+            end,
+            store,
+            load,
+            shift_left,
+            shift_right,
+            not,
+            and,
+            or,
+            xor
+        )).map(|ins| (ins, Vec::new()));
+
+        let bfloop = between(byte(b'['), byte(b']'), program(extensions))
+            .map(|(body, map)| (Loop(body), map));
+        let procedure = between(byte(b'('), byte(b')'), program(extensions))
+            .map(|(body, map)| (Procedure(body), map));
+
+        let instruction = (position(), choice((simple, bfloop, procedure)), position())
+            .map(|(start, (ins, body), end)| (ins, SourceNode { span: Span { start, end }, body }))
+            .skip(comments());
 
         (
             comments(),
-            many(instruction)
-        ).map(|(_comments, instructions)| instructions)
+            many::<Vec<_>, _>(instruction)
+        ).map(|(_comments, instructions)| instructions.into_iter().unzip())
     }
 }
 
 /// Parses Brainfuck source and returns a [Program](type.Program.html).
 pub fn parse<R: Read>(input: R) -> Program {
-    let stream = BufferedStream::new(State::new(ReadStream::new(input)), 1);
-    let ((prog, _eof), _state) = (program(), eof()).parse(stream).unwrap();
-    prog
+    Extensions::default().parse(input)
+}
+
+/// Parses Brainfuck source and returns a [Program](type.Program.html) together with the
+/// [SourceMap](type.SourceMap.html) locating each instruction in the source.
+pub fn parse_with_spans<R: Read>(input: R) -> (Program, SourceMap) {
+    Extensions::default().parse_with_spans(input)
 }
@@ -0,0 +1,163 @@
+use std::ops::ControlFlow;
+
+use crate::ast::{Instruction, Instruction::*, Program};
+use crate::interpreter::{Hooks, Interpreter, Iteration, Tape};
+
+/// Instructions evaluated before giving up, so programs that run forever without reading
+/// input still compile.
+const BUDGET: u64 = 1 << 20;
+
+/// Cells the evaluated prefix may use, counted from the starting cell.
+const MAX_CELLS: usize = 1 << 16;
+
+/// A zeroed tape that grows up to [`MAX_CELLS`] as the program uses it.
+#[derive(Clone)]
+struct Cells(Vec<u8>);
+
+/// Collects the output of a program evaluated at compile time, and gives up on anything that
+/// can't be evaluated.
+#[derive(Clone, Default)]
+struct Evaluation {
+    output: Vec<u8>,
+    steps: u64,
+}
+
+/// Evaluates `program` from its start until the first instruction that depends on input or
+/// can't be evaluated at compile time, like `Read`, procedures or the storage register.
+///
+/// The evaluated prefix is replaced with the output it produced and the cells it left behind,
+/// so a program that never reads input becomes a single write. The cells are only restored
+/// without any code left if `final_tape` is set. Top-level instructions are evaluated as a
+/// whole: if a loop reads input, the program continues at that loop.
+pub(crate) fn partial_eval(program: Program, final_tape: bool) -> Program {
+    let mut eval = Interpreter::new(Cells(vec![0]), 0, Evaluation::default());
+    let mut evaluated = 0;
+
+    for ins in &program {
+        // Instructions that repeat may give up halfway through, so they run on a copy.
+        let backup = matches!(ins, Scan(_) | Loop(_) | BalancedLoop(_)).then(|| eval.clone());
+
+        if eval.instruction(ins).is_break() {
+            eval = backup.unwrap_or(eval);
+            break;
+        }
+
+        evaluated += 1;
+    }
+
+    if evaluated == 0 {
+        return program;
+    }
+
+    let rest = &program[evaluated..];
+    let restore = final_tape || !rest.is_empty();
+    let mut out = Vec::new();
+
+    // Without any code left, the cells only matter to callers that look at the final tape.
+    if restore {
+        let mut ptr = 0;
+
+        for (cell, &value) in eval.tape.0.iter().enumerate() {
+            if value != 0 && cell != eval.ptr {
+                push_move(&mut out, cell as i64 - ptr as i64);
+                out.push(Set(value as i64));
+                ptr = cell;
+            }
+        }
+
+        push_move(&mut out, eval.ptr as i64 - ptr as i64);
+    }
+
+    // Writes leave their last byte in the current cell.
+    let current = match eval.hooks.output[..] {
+        [] => 0,
+        [byte] => {
+            out.push(WriteConst(byte as i64));
+            byte
+        }
+        [.., last] => {
+            out.push(WriteBytes(eval.hooks.output.clone()));
+            last
+        }
+    };
+
+    if restore && eval.get() != current {
+        out.push(Set(eval.get() as i64));
+    }
+
+    out.extend_from_slice(rest);
+    out
+}
+
+fn push_move(out: &mut Program, n: i64) {
+    if n != 0 {
+        out.push(Move(n));
+    }
+}
+
+impl Tape for Cells {
+    fn cells(&self) -> &[u8] {
+        &self.0
+    }
+
+    fn cells_mut(&mut self) -> &mut [u8] {
+        &mut self.0
+    }
+
+    fn contains(&mut self, cell: usize) -> bool {
+        if cell >= MAX_CELLS {
+            return false;
+        }
+
+        if cell >= self.0.len() {
+            self.0.resize(cell + 1, 0);
+        }
+
+        true
+    }
+}
+
+impl<'p> Hooks<'p, Cells> for Evaluation {
+    /// Counts an evaluated instruction, or gives up on it if it can't be evaluated at compile
+    /// time or the budget is used up.
+    fn instruction(&mut self, ins: &'p Instruction) -> ControlFlow<()> {
+        match ins {
+            Read | Procedure(_) | Call | End | Store | Load | And | Or | Xor => {
+                ControlFlow::Break(())
+            }
+            _ => self.step(),
+        }
+    }
+
+    fn iteration(
+        &mut self,
+        _ins: &'p Instruction,
+        _tape: &mut Cells,
+        _ptr: &mut usize,
+    ) -> ControlFlow<(), Iteration> {
+        self.step()?;
+        ControlFlow::Continue(Iteration::Interpret)
+    }
+
+    fn read(&mut self) -> u8 {
+        unreachable!("input is never evaluated")
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        self.output.extend_from_slice(bytes);
+    }
+}
+
+impl Evaluation {
+    /// Counts an evaluated instruction or loop iteration, and breaks once the budget is used
+    /// up.
+    fn step(&mut self) -> ControlFlow<()> {
+        self.steps += 1;
+
+        if self.steps <= BUDGET {
+            ControlFlow::Continue(())
+        } else {
+            ControlFlow::Break(())
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::ast::{Instruction, Instruction::*, Program};
+
+/// How deeply procedure calls may nest, which keeps recursive procedures from overflowing the
+/// stack.
+const MAX_CALL_DEPTH: usize = 1_000;
+
+/// A deliberately naive interpreter that defines what a program means.
+///
+/// It runs one instruction at a time on a bounds-checked tape, without any of the shortcuts
+/// the optimizer and the backends take, so its results can be trusted when checking them with
+/// [`verify`](crate::verify). Input comes from a byte slice, reading past its end gives `0`,
+/// and output is collected instead of written to stdout.
+#[derive(Clone, Debug)]
+pub struct Reference {
+    tape_size: usize,
+    max_steps: u64,
+}
+
+/// What a program did: the bytes it wrote and the tape it left behind.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Execution {
+    /// Every byte the program wrote, in order.
+    pub output: Vec<u8>,
+    /// The tape after the program ended.
+    pub tape: Vec<u8>,
+    /// The index of the cell the data pointer ended on.
+    pub ptr: usize,
+}
+
+/// Why the [`Reference`] interpreter couldn't run a program to its end.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReferenceError {
+    /// The program ran more steps than allowed, it might never end.
+    StepLimit,
+    /// The data pointer left the tape.
+    OutOfBounds,
+    /// Procedure calls nested too deeply.
+    CallDepth,
+}
+
+struct Run<'p, 'i> {
+    tape: Vec<u8>,
+    ptr: usize,
+    storage: u8,
+    input: &'i [u8],
+    output: Vec<u8>,
+    procedures: Vec<Option<&'p Program>>,
+    steps: u64,
+    max_steps: u64,
+    depth: usize,
+}
+
+/// How execution continues after an instruction.
+enum Flow {
+    Next,
+    End,
+}
+
+impl Reference {
+    /// Initializes a `Reference` with a tape size of `30_000` and a limit of `10_000_000` steps
+    pub fn new() -> Reference {
+        Reference {
+            tape_size: 30_000,
+            max_steps: 10_000_000,
+        }
+    }
+
+    /// Sets the tape size
+    pub fn set_tape_size(mut self, tape_size: usize) -> Self {
+        self.tape_size = tape_size;
+        self
+    }
+
+    /// Sets how many instructions and loop iterations may run before giving up
+    pub fn set_max_steps(mut self, max_steps: u64) -> Self {
+        self.max_steps = max_steps;
+        self
+    }
+
+    /// The tape size programs run on.
+    pub fn tape_size(&self) -> usize {
+        self.tape_size
+    }
+
+    /// Runs `program` on a fresh, zeroed tape with the data pointer at cell `0`.
+    pub fn run(&self, program: &Program, input: &[u8]) -> Result<Execution, ReferenceError> {
+        let mut run = Run {
+            tape: vec![0; self.tape_size],
+            ptr: 0,
+            storage: 0,
+            input,
+            output: Vec::new(),
+            procedures: vec![None; 256],
+            steps: 0,
+            max_steps: self.max_steps,
+            depth: 0,
+        };
+
+        run.program(program)?;
+
+        Ok(Execution {
+            output: run.output,
+            tape: run.tape,
+            ptr: run.ptr,
+        })
+    }
+}
+
+impl Default for Reference {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'p> Run<'p, '_> {
+    fn program(&mut self, program: &'p Program) -> Result<Flow, ReferenceError> {
+        for ins in program {
+            if let Flow::End = self.instruction(ins)? {
+                return Ok(Flow::End);
+            }
+        }
+
+        Ok(Flow::Next)
+    }
+
+    fn instruction(&mut self, ins: &'p Instruction) -> Result<Flow, ReferenceError> {
+        self.step()?;
+
+        match *ins {
+            Add(n) => self.set(self.get().wrapping_add(n as u8)),
+            Move(n) => self.ptr = self.cell(n)?,
+            Set(n) => self.set(n as u8),
+            Mul(offset, factor) => self.transfer(offset, factor)?,
+            MulRun(ref muls) => {
+                for &(offset, factor) in muls {
+                    self.transfer(offset, factor)?;
+                }
+                self.set(0);
+            }
+            Scan(n) => {
+                while self.get() != 0 {
+                    self.step()?;
+                    self.ptr = self.cell(n)?;
+                }
+            }
+            Read => {
+                let byte = match self.input.split_first() {
+                    Some((&byte, rest)) => {
+                        self.input = rest;
+                        byte
+                    }
+                    None => 0,
+                };
+                self.set(byte);
+            }
+            Write => self.output.push(self.get()),
+            WriteConst(n) => {
+                self.set(n as u8);
+                self.output.push(n as u8);
+            }
+            WriteBytes(ref bytes) => {
+                for &byte in bytes {
+                    self.set(byte);
+                    self.output.push(byte);
+                }
+            }
+            Loop(ref body) => {
+                while self.get() != 0 {
+                    self.step()?;
+
+                    if let Flow::End = self.program(body)? {
+                        return Ok(Flow::End);
+                    }
+                }
+            }
+            BalancedLoop(ref body) => {
+                let entry = self.ptr;
+
+                while self.get() != 0 {
+                    self.step()?;
+
+                    for (offset, ins) in body {
+                        self.ptr = self.cell(*offset)?;
+                        let flow = self.instruction(ins)?;
+                        self.ptr = entry;
+
+                        if let Flow::End = flow {
+                            return Ok(Flow::End);
+                        }
+                    }
+                }
+            }
+            Procedure(ref body) => {
+                let index = self.get() as usize;
+                self.procedures[index] = Some(body);
+            }
+            Call => {
+                if let Some(body) = self.procedures[self.get() as usize] {
+                    if self.depth == MAX_CALL_DEPTH {
+                        return Err(ReferenceError::CallDepth);
+                    }
+
+                    self.depth += 1;
+                    let flow = self.program(body)?;
+                    self.depth -= 1;
+                    return Ok(flow);
+                }
+            }
+            End => return Ok(Flow::End),
+            Store => self.storage = self.get(),
+            Load => self.set(self.storage),
+            Shift(n) => {
+                let value = self.get();
+                let shifted = match n {
+                    0..8 => value << n,
+                    -7..0 => value >> -n,
+                    _ => 0,
+                };
+                self.set(shifted);
+            }
+            Not => self.set(!self.get()),
+            And => self.set(self.get() & self.storage),
+            Or => self.set(self.get() | self.storage),
+            Xor => self.set(self.get() ^ self.storage),
+        }
+
+        Ok(Flow::Next)
+    }
+
+    fn step(&mut self) -> Result<(), ReferenceError> {
+        self.steps += 1;
+
+        if self.steps > self.max_steps {
+            return Err(ReferenceError::StepLimit);
+        }
+
+        Ok(())
+    }
+
+    /// The index of the cell at `offset` from the current one.
+    fn cell(&self, offset: i64) -> Result<usize, ReferenceError> {
+        (self.ptr as i64)
+            .checked_add(offset)
+            .and_then(|cell| usize::try_from(cell).ok())
+            .filter(|&cell| cell < self.tape.len())
+            .ok_or(ReferenceError::OutOfBounds)
+    }
+
+    fn get(&self) -> u8 {
+        self.tape[self.ptr]
+    }
+
+    fn set(&mut self, value: u8) {
+        self.tape[self.ptr] = value;
+    }
+
+    fn transfer(&mut self, offset: i64, factor: i64) -> Result<(), ReferenceError> {
+        let dest = self.cell(offset)?;
+        self.tape[dest] = self.tape[dest].wrapping_add(self.get().wrapping_mul(factor as u8));
+        Ok(())
+    }
+}
+
+impl fmt::Display for ReferenceError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReferenceError::StepLimit => write!(f, "the program ran too many steps"),
+            ReferenceError::OutOfBounds => write!(f, "the data pointer left the tape"),
+            ReferenceError::CallDepth => write!(f, "procedure calls nested too deeply"),
+        }
+    }
+}
+
+impl Error for ReferenceError {}
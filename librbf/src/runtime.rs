@@ -0,0 +1,356 @@
+use std::io::{self, BufRead, IsTerminal, Read, Write};
+use std::mem::{self, offset_of};
+
+/// How many bytes of output are buffered before they are written, and how many bytes of input
+/// are read at once.
+const BUFFER_CAPACITY: usize = 1 << 16;
+
+/// When buffered output is written to stdout.
+///
+/// Output is always written before waiting for input and when the program ends, so prompts
+/// show up before the program waits for an answer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Flush {
+    /// After every byte.
+    Always,
+    /// After every newline.
+    Line,
+    /// Only when the buffer is full.
+    #[default]
+    Exit,
+}
+
+/// What `,` and `.` read and write.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IoMode {
+    /// Single bytes.
+    #[default]
+    Bytes,
+    /// Decimal numbers. `.` writes the cell as a number followed by a newline, `,` skips
+    /// leading whitespace and reads a number up to the next byte that isn't a digit. Numbers
+    /// wrap around like the cells, and `-` in front of a number negates it.
+    Numeric,
+}
+
+/// Formats `bytes` as numbers the way `.` writes them in [`IoMode::Numeric`].
+pub(crate) fn numbers(bytes: &[u8]) -> Vec<u8> {
+    let mut text = Vec::with_capacity(bytes.len() * 4);
+
+    for byte in bytes {
+        writeln!(text, "{}", byte).unwrap();
+    }
+
+    text
+}
+
+/// How written bytes are shown.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// The bytes themselves.
+    #[default]
+    Raw,
+    /// Every byte as the Latin-1 character with that code point, encoded as UTF-8.
+    Utf8Latin1,
+    /// Every byte as a decimal number. Numbers are separated by spaces and the output ends
+    /// with a newline.
+    Decimal,
+    /// Every byte as two hexadecimal digits. Numbers are separated by spaces and the output
+    /// ends with a newline.
+    Hex,
+}
+
+/// Where output goes once it leaves the buffer.
+struct Sink {
+    format: OutputFormat,
+    /// Whether a number was written, so the next one needs a separator.
+    separate: bool,
+    /// The output of a captured run, which doesn't write to stdout.
+    capture: Option<Vec<u8>>,
+}
+
+/// The state of a running program besides its tape, shared by generated code, the helpers it
+/// calls and the interpreters.
+///
+/// Generated code gets a pointer to the context. It appends written bytes to the output
+/// buffer itself while `output_len` is below `output_limit`, and takes bytes from the input
+/// buffer while `input_pos` is below `input_limit`. Otherwise it calls a helper, which
+/// flushes or refills the buffer. The output limit is `0` unless output is only flushed when
+/// the buffer is full, so the helpers see every byte that may have to be flushed. The input
+/// limit is `0` when reading from a terminal, so output is flushed before every read and
+/// prompts show up even if the user typed ahead.
+#[repr(C)]
+pub(crate) struct Context {
+    output: *mut u8,
+    output_len: usize,
+    output_limit: usize,
+    input: *const u8,
+    input_pos: usize,
+    input_limit: usize,
+    output_buffer: Box<[u8]>,
+    input_buffer: Box<[u8]>,
+    input_len: usize,
+    /// Whether input comes from stdin instead of a captured slice.
+    stdin: bool,
+    /// Whether stdin is read one line at a time instead of in blocks.
+    line_input: bool,
+    interactive: bool,
+    flush: Flush,
+    sink: Sink,
+}
+
+/// Offsets of the fields generated code accesses.
+pub(crate) const CONTEXT_OUTPUT: usize = offset_of!(Context, output);
+pub(crate) const CONTEXT_OUTPUT_LEN: usize = offset_of!(Context, output_len);
+pub(crate) const CONTEXT_OUTPUT_LIMIT: usize = offset_of!(Context, output_limit);
+pub(crate) const CONTEXT_INPUT: usize = offset_of!(Context, input);
+pub(crate) const CONTEXT_INPUT_POS: usize = offset_of!(Context, input_pos);
+pub(crate) const CONTEXT_INPUT_LIMIT: usize = offset_of!(Context, input_limit);
+
+impl Context {
+    /// Initializes a `Context` that reads from stdin and writes to stdout.
+    pub(crate) fn new(flush: Flush, format: OutputFormat) -> Context {
+        let mut output_buffer = vec![0; BUFFER_CAPACITY].into_boxed_slice();
+        let input_buffer = vec![0; BUFFER_CAPACITY].into_boxed_slice();
+
+        Context {
+            output: output_buffer.as_mut_ptr(),
+            output_len: 0,
+            output_limit: match flush {
+                Flush::Exit => output_buffer.len(),
+                Flush::Always | Flush::Line => 0,
+            },
+            input: input_buffer.as_ptr(),
+            input_pos: 0,
+            input_limit: 0,
+            output_buffer,
+            input_buffer,
+            input_len: 0,
+            stdin: true,
+            line_input: false,
+            interactive: io::stdin().is_terminal(),
+            flush,
+            sink: Sink {
+                format,
+                separate: false,
+                capture: None,
+            },
+        }
+    }
+
+    /// Initializes a `Context` that reads from `input` and collects the output, see
+    /// [`into_output`](Context::into_output).
+    pub(crate) fn captured(input: &[u8]) -> Context {
+        let mut context = Context::new(Flush::Exit, OutputFormat::Raw);
+        context.input_buffer = input.into();
+        context.input = context.input_buffer.as_ptr();
+        context.input_len = input.len();
+        context.input_limit = input.len();
+        context.stdin = false;
+        context.interactive = false;
+        context.sink.capture = Some(Vec::new());
+        context
+    }
+
+    /// Sets whether stdin is read one line at a time. The rest of the input then stays in
+    /// stdin's buffer, where the caller can read it after the run.
+    pub(crate) fn set_line_input(mut self, line_input: bool) -> Self {
+        self.line_input = line_input;
+        self
+    }
+
+    pub(crate) fn write(&mut self, bytes: &[u8]) {
+        if self.output_len + bytes.len() > self.output_buffer.len() {
+            self.flush().unwrap();
+        }
+
+        if bytes.len() > self.output_buffer.len() {
+            self.sink.write(bytes).unwrap();
+            return;
+        }
+
+        self.output_buffer[self.output_len..self.output_len + bytes.len()].copy_from_slice(bytes);
+        self.output_len += bytes.len();
+
+        match self.flush {
+            Flush::Always => self.flush().unwrap(),
+            Flush::Line if bytes.contains(&b'\n') => self.flush().unwrap(),
+            Flush::Line | Flush::Exit => {}
+        }
+    }
+
+    pub(crate) fn write_number(&mut self, byte: u8) {
+        self.write(&numbers(&[byte]));
+    }
+
+    /// Reads a byte, or returns `0` at the end of the input.
+    pub(crate) fn read(&mut self) -> u8 {
+        if self.interactive {
+            self.flush().unwrap();
+        }
+
+        self.next_input().unwrap_or(0)
+    }
+
+    /// Reads a number, see [`IoMode::Numeric`]. Returns `0` if the input ends before the
+    /// number starts.
+    pub(crate) fn read_number(&mut self) -> u8 {
+        if self.interactive {
+            self.flush().unwrap();
+        }
+
+        let mut next = self.next_input();
+        while next.is_some_and(|byte| byte.is_ascii_whitespace()) {
+            next = self.next_input();
+        }
+
+        let negative = next == Some(b'-');
+        if negative {
+            next = self.next_input();
+        }
+
+        let mut value = 0u8;
+        while let Some(digit @ b'0'..=b'9') = next {
+            value = value.wrapping_mul(10).wrapping_add(digit - b'0');
+            next = self.next_input();
+        }
+
+        if negative {
+            value.wrapping_neg()
+        } else {
+            value
+        }
+    }
+
+    fn next_input(&mut self) -> Option<u8> {
+        if self.input_pos == self.input_len {
+            self.refill();
+        }
+
+        let byte = *self.input_buffer[..self.input_len].get(self.input_pos)?;
+        self.input_pos += 1;
+        Some(byte)
+    }
+
+    /// Replaces the used up input buffer with the next block of input. The buffered output is
+    /// written first, since reading may wait for the user.
+    fn refill(&mut self) {
+        self.flush().unwrap();
+        self.input_pos = 0;
+        self.input_len = 0;
+
+        if self.stdin && self.line_input {
+            let mut stdin = io::stdin().lock();
+
+            let available = loop {
+                match stdin.fill_buf() {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result.unwrap_or(&[]),
+                }
+            };
+            let line = match available.iter().position(|&byte| byte == b'\n') {
+                Some(end) => &available[..end + 1],
+                None => available,
+            };
+            let len = line.len().min(self.input_buffer.len());
+
+            self.input_buffer[..len].copy_from_slice(&line[..len]);
+            stdin.consume(len);
+            self.input_len = len;
+        } else if self.stdin {
+            let mut stdin = io::stdin().lock();
+
+            self.input_len = loop {
+                match stdin.read(&mut self.input_buffer) {
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    result => break result.unwrap_or(0),
+                }
+            };
+        }
+
+        if !self.interactive {
+            self.input_limit = self.input_len;
+        }
+    }
+
+    /// Writes the buffered output.
+    pub(crate) fn flush(&mut self) -> io::Result<()> {
+        let len = mem::take(&mut self.output_len);
+        self.sink.write(&self.output_buffer[..len])
+    }
+
+    /// Writes the buffered output and ends the output once the run is over.
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()?;
+        self.sink.finish()
+    }
+
+    /// Returns everything a captured run wrote.
+    pub(crate) fn into_output(mut self) -> Vec<u8> {
+        self.finish().unwrap();
+        self.sink.capture.take().unwrap_or_default()
+    }
+}
+
+impl Drop for Context {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+impl Sink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        if self.format == OutputFormat::Raw {
+            return self.emit(bytes);
+        }
+
+        let mut text = Vec::with_capacity(bytes.len() * 4);
+
+        for &byte in bytes {
+            match self.format {
+                OutputFormat::Raw => text.push(byte),
+                OutputFormat::Utf8Latin1 => {
+                    text.extend_from_slice((byte as char).encode_utf8(&mut [0; 2]).as_bytes())
+                }
+                OutputFormat::Decimal | OutputFormat::Hex => {
+                    if self.separate {
+                        text.push(b' ');
+                    }
+
+                    self.separate = true;
+
+                    if self.format == OutputFormat::Decimal {
+                        write!(text, "{}", byte)?;
+                    } else {
+                        write!(text, "{:02x}", byte)?;
+                    }
+                }
+            }
+        }
+
+        self.emit(&text)
+    }
+
+    /// Ends a line of numbers.
+    fn finish(&mut self) -> io::Result<()> {
+        if mem::take(&mut self.separate) {
+            return self.emit(b"\n");
+        }
+
+        Ok(())
+    }
+
+    /// Writes `bytes` to the capture if there is one, and to stdout otherwise.
+    fn emit(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match &mut self.capture {
+            Some(capture) => {
+                capture.extend_from_slice(bytes);
+                Ok(())
+            }
+            None => {
+                let mut stdout = io::stdout().lock();
+                stdout.write_all(bytes)?;
+                stdout.flush()
+            }
+        }
+    }
+}
@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::ast::{Instruction, Instruction::*, Program};
+use crate::jit::Function;
+use crate::opt::PassManager;
+
+/// Statistics about how well a program was optimized.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Brainfuck commands in the source, counting both brackets of loops and procedures.
+    pub source_commands: usize,
+    /// Instructions after parsing, which already groups runs of the same command, including
+    /// loop and procedure bodies.
+    pub parsed_instructions: usize,
+    /// Instructions of each kind after optimization, including loop and procedure bodies.
+    pub instructions: BTreeMap<&'static str, usize>,
+    /// What the loops of the source became.
+    pub loops: LoopStats,
+    /// Size of the generated machine code in bytes, if it was compiled.
+    pub code_size: Option<usize>,
+}
+
+/// What the loops of a program became, each optimized on its own.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LoopStats {
+    /// Loops that became `Set`.
+    pub set: usize,
+    /// Loops that became `Scan`.
+    pub scan: usize,
+    /// Loops that became `MulRun`.
+    pub mul_run: usize,
+    /// Loops that became `BalancedLoop`.
+    pub balanced: usize,
+    /// Loops that became something else, e.g. nothing at all.
+    pub other: usize,
+    /// Loops that stayed loops, by the reason they couldn't be replaced.
+    pub kept: BTreeMap<LoopReason, usize>,
+}
+
+/// Why a loop stayed a loop.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LoopReason {
+    /// The body reads input or writes output.
+    Io,
+    /// The body contains loops, scans or procedures.
+    Nested,
+    /// The body moves the data pointer.
+    Unbalanced,
+    /// The body uses instructions other than `Add` and `Move`.
+    Other,
+    /// The body changes the loop cell by an even amount, so the loop might never end.
+    Step,
+}
+
+impl Stats {
+    /// Optimizes `program` with `manager` and collects statistics about the result.
+    pub fn collect(program: &Program, manager: &PassManager) -> Stats {
+        let mut stats = Stats {
+            source_commands: source_commands(program),
+            parsed_instructions: instruction_count(program),
+            ..Default::default()
+        };
+
+        // Loops are optimized on their own, which only says something about the loop itself if
+        // it may start with any cell values.
+        stats.count_loops(program, &manager.clone().assume_zeroed_tape(false));
+        stats.count_instructions(&manager.run(program.clone()));
+
+        stats
+    }
+
+    /// Adds the size of the code compiled for the optimized program.
+    pub fn set_code_size(mut self, function: &Function) -> Self {
+        self.code_size = Some(function.code_size());
+        self
+    }
+
+    /// The number of instructions after optimization.
+    pub fn optimized_instructions(&self) -> usize {
+        self.instructions.values().sum()
+    }
+
+    fn count_instructions(&mut self, program: &Program) {
+        for ins in program {
+            self.count_instruction(ins);
+        }
+    }
+
+    fn count_instruction(&mut self, ins: &Instruction) {
+        *self.instructions.entry(kind(ins)).or_default() += 1;
+
+        match ins {
+            Loop(body) | Procedure(body) => self.count_instructions(body),
+            BalancedLoop(body) => {
+                for (_, ins) in body {
+                    self.count_instruction(ins);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn count_loops(&mut self, program: &Program, manager: &PassManager) {
+        for ins in program {
+            match ins {
+                Loop(body) => {
+                    let loops = &mut self.loops;
+
+                    match &manager.run(vec![ins.clone()])[..] {
+                        [Set(_)] => loops.set += 1,
+                        [Scan(_)] => loops.scan += 1,
+                        [MulRun(_)] => loops.mul_run += 1,
+                        [BalancedLoop(_)] => loops.balanced += 1,
+                        [Loop(body)] => *loops.kept.entry(LoopReason::of(body)).or_default() += 1,
+                        _ => loops.other += 1,
+                    }
+
+                    self.count_loops(body, manager);
+                }
+                Procedure(body) => self.count_loops(body, manager),
+                _ => (),
+            }
+        }
+    }
+}
+
+impl LoopStats {
+    /// The number of loops in the source.
+    pub fn total(&self) -> usize {
+        self.set
+            + self.scan
+            + self.mul_run
+            + self.balanced
+            + self.other
+            + self.kept.values().sum::<usize>()
+    }
+}
+
+impl LoopReason {
+    fn of(body: &Program) -> LoopReason {
+        let has = |f: fn(&Instruction) -> bool| body.iter().any(f);
+
+        if has(|ins| matches!(ins, Read | Write | WriteConst(_) | WriteBytes(_))) {
+            LoopReason::Io
+        } else if has(|ins| matches!(ins, Loop(_) | Scan(_) | Procedure(_) | Call)) {
+            LoopReason::Nested
+        } else if body
+            .iter()
+            .map(|ins| if let Move(n) = ins { *n } else { 0 })
+            .sum::<i64>()
+            != 0
+        {
+            LoopReason::Unbalanced
+        } else if has(|ins| !matches!(ins, Add(_) | Move(_))) {
+            LoopReason::Other
+        } else {
+            LoopReason::Step
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            LoopReason::Io => "input or output",
+            LoopReason::Nested => "nested control flow",
+            LoopReason::Unbalanced => "moves the data pointer",
+            LoopReason::Other => "other instructions",
+            LoopReason::Step => "loop cell changed by an even amount",
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "source commands: {}", self.source_commands)?;
+        writeln!(f, "parsed instructions: {}", self.parsed_instructions)?;
+        writeln!(
+            f,
+            "optimized instructions: {}",
+            self.optimized_instructions()
+        )?;
+
+        for (kind, count) in &self.instructions {
+            writeln!(f, "  {}: {}", kind, count)?;
+        }
+
+        writeln!(f, "loops: {}", self.loops.total())?;
+        writeln!(f, "  became Set: {}", self.loops.set)?;
+        writeln!(f, "  became Scan: {}", self.loops.scan)?;
+        writeln!(f, "  became MulRun: {}", self.loops.mul_run)?;
+        writeln!(f, "  became BalancedLoop: {}", self.loops.balanced)?;
+        writeln!(f, "  became something else: {}", self.loops.other)?;
+        writeln!(f, "  kept: {}", self.loops.kept.values().sum::<usize>())?;
+
+        for (reason, count) in &self.loops.kept {
+            writeln!(f, "    {}: {}", reason.description(), count)?;
+        }
+
+        if let Some(size) = self.code_size {
+            writeln!(f, "code size ({}): {} bytes", std::env::consts::ARCH, size)?;
+        }
+
+        Ok(())
+    }
+}
+
+fn instruction_count(program: &Program) -> usize {
+    program.iter().map(size).sum()
+}
+
+/// The number of instructions in `ins`, including its body.
+fn size(ins: &Instruction) -> usize {
+    match ins {
+        Loop(body) | Procedure(body) => 1 + instruction_count(body),
+        BalancedLoop(body) => 1 + body.iter().map(|(_, ins)| size(ins)).sum::<usize>(),
+        _ => 1,
+    }
+}
+
+/// Counts the source commands an unoptimized program was parsed from.
+fn source_commands(program: &Program) -> usize {
+    program
+        .iter()
+        .map(|ins| match ins {
+            Add(n) | Move(n) | Shift(n) => n.unsigned_abs() as usize,
+            Loop(body) | Procedure(body) => 2 + source_commands(body),
+            _ => 1,
+        })
+        .sum()
+}
+
+fn kind(ins: &Instruction) -> &'static str {
+    match ins {
+        Add(_) => "Add",
+        Move(_) => "Move",
+        Set(_) => "Set",
+        Mul(..) => "Mul",
+        MulRun(_) => "MulRun",
+        Scan(_) => "Scan",
+        Read => "Read",
+        Write => "Write",
+        WriteConst(_) => "WriteConst",
+        WriteBytes(_) => "WriteBytes",
+        Loop(_) => "Loop",
+        BalancedLoop(_) => "BalancedLoop",
+        Procedure(_) => "Procedure",
+        Call => "Call",
+        End => "End",
+        Store => "Store",
+        Load => "Load",
+        Shift(_) => "Shift",
+        Not => "Not",
+        And => "And",
+        Or => "Or",
+        Xor => "Xor",
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+use std::ops::ControlFlow;
+use std::rc::Rc;
+
+use crate::ast::{Instruction::*, *};
+use crate::interpreter::{Hooks, Interpreter, Iteration, Stop};
+use crate::jit::common::GuardedTape;
+use crate::jit::{Function, Jit};
+use crate::runtime::{Context, Flush, IoMode, OutputFormat, numbers};
+
+/// Runs programs on an interpreter and JIT-compiles hot loops.
+///
+/// Every program starts on the interpreter, so there is no upfront compile time. Once the body
+/// of a loop has run more than the threshold number of times, the loop is compiled on its own,
+/// like with [`Jit::compile_loop`], and its remaining iterations run natively on the same
+/// tape.
+///
+/// Loops using procedures, `End` or the storage register stay on the interpreter because the
+/// compiled code doesn't share the interpreter's procedures and storage register. So do loops
+/// that move more than about a million cells between two accesses to the tape, since the guards
+/// around the tape wouldn't stop them from leaving it.
+#[derive(Clone, Debug)]
+pub struct Tiered {
+    tape_size: usize,
+    threshold: u64,
+    io_mode: IoMode,
+    flush: Flush,
+    output_format: OutputFormat,
+}
+
+/// The reach of the compiled loops the guards around the interpreter's tape protect against.
+const GUARDED_REACH: u64 = 1 << 20;
+
+enum Tier {
+    Interpreted(u64),
+    Compiled(Rc<Function>),
+    Ineligible,
+}
+
+/// Counts loop iterations for the interpreter and runs hot loops as compiled code.
+struct Tiering<'a> {
+    tiered: &'a Tiered,
+    context: &'a mut Context,
+    loops: HashMap<*const Instruction, Tier>,
+    compiled: usize,
+    // Set when a compiled loop leaves the data pointer outside the tape.
+    left_tape: bool,
+}
+
+impl Tiered {
+    /// Initializes a `Tiered` with a tape size of `30_000` and a threshold of `1_000`
+    pub fn new() -> Tiered {
+        Tiered {
+            tape_size: 30_000,
+            threshold: 1_000,
+            io_mode: IoMode::default(),
+            flush: Flush::default(),
+            output_format: OutputFormat::default(),
+        }
+    }
+
+    /// Sets the tape size
+    pub fn set_tape_size(mut self, tape_size: usize) -> Self {
+        self.tape_size = tape_size;
+        self
+    }
+
+    /// Sets how many iterations of a loop body are interpreted before the loop is compiled
+    pub fn set_threshold(mut self, threshold: u64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Sets what `,` and `.` read and write, see [`IoMode`]. Defaults to bytes.
+    pub fn set_io_mode(mut self, mode: IoMode) -> Self {
+        self.io_mode = mode;
+        self
+    }
+
+    /// Sets when output is written to stdout. Defaults to [`Flush::Exit`].
+    pub fn set_flush(mut self, flush: Flush) -> Self {
+        self.flush = flush;
+        self
+    }
+
+    /// Sets how written bytes are shown. Defaults to [`OutputFormat::Raw`].
+    pub fn set_output_format(mut self, format: OutputFormat) -> Self {
+        self.output_format = format;
+        self
+    }
+
+    /// Runs `program` on a fresh, zeroed tape and returns how many loops were compiled.
+    ///
+    /// Panics if the data pointer leaves the tape.
+    pub fn run(&self, program: &Program) -> usize {
+        let mut tape = vec![0; self.tape_size];
+        let mut context = self.context();
+        let (ptr, compiled) = self.run_on(program, &mut tape, 0, &mut context);
+        ptr.expect("data pointer moved outside the tape");
+        compiled
+    }
+
+    /// A run context with this `Tiered`'s flush policy and output format.
+    pub(crate) fn context(&self) -> Context {
+        Context::new(self.flush, self.output_format)
+    }
+
+    /// Runs `program` on `tape` from cell `start` with input and output going through
+    /// `context`, and returns the cell the data pointer ended on and how many loops were
+    /// compiled. The cell is `None` if the program moved the data pointer off the tape, which
+    /// stops it.
+    pub(crate) fn run_on(
+        &self,
+        program: &Program,
+        tape: &mut [u8],
+        start: usize,
+        context: &mut Context,
+    ) -> (Option<usize>, usize) {
+        assert!(start < tape.len(), "start outside the tape");
+
+        // Compiled loops don't check their moves, so everything runs on a copy of the tape
+        // between guards.
+        let mut guarded =
+            GuardedTape::new(tape.len(), GUARDED_REACH).expect("could not map the tape");
+        guarded.copy_from_slice(tape);
+
+        let tiering = Tiering {
+            tiered: self,
+            context,
+            loops: HashMap::new(),
+            compiled: 0,
+            left_tape: false,
+        };
+        let mut interpreter = Interpreter::new(&mut *guarded, start, tiering);
+
+        let left_tape = interpreter.execute(program) == ControlFlow::Break(Stop::LeftTape)
+            || interpreter.hooks.left_tape;
+        let ptr = (!left_tape).then_some(interpreter.ptr);
+        let compiled = interpreter.hooks.compiled;
+
+        tape.copy_from_slice(&guarded);
+        (ptr, compiled)
+    }
+}
+
+impl Default for Tiered {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'p> Hooks<'p, &mut [u8]> for Tiering<'_> {
+    fn iteration(
+        &mut self,
+        ins: &'p Instruction,
+        tape: &mut &mut [u8],
+        ptr: &mut usize,
+    ) -> ControlFlow<(), Iteration> {
+        if let Scan(_) = ins {
+            return ControlFlow::Continue(Iteration::Interpret);
+        }
+
+        let Some(function) = self.hot_loop(ins) else {
+            return ControlFlow::Continue(Iteration::Interpret);
+        };
+
+        match self.run_compiled(&function, tape, *ptr) {
+            Some(end) => {
+                *ptr = end;
+                ControlFlow::Continue(Iteration::Finished)
+            }
+            None => {
+                self.left_tape = true;
+                ControlFlow::Break(())
+            }
+        }
+    }
+
+    fn read(&mut self) -> u8 {
+        match self.tiered.io_mode {
+            IoMode::Bytes => self.context.read(),
+            IoMode::Numeric => self.context.read_number(),
+        }
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        match self.tiered.io_mode {
+            IoMode::Bytes => self.context.write(bytes),
+            IoMode::Numeric => self.context.write(&numbers(bytes)),
+        }
+    }
+}
+
+impl Tiering<'_> {
+    /// Counts an iteration of the loop `ins` and returns its compiled code once the loop is
+    /// hot.
+    fn hot_loop(&mut self, ins: &Instruction) -> Option<Rc<Function>> {
+        let threshold = self.tiered.threshold;
+        let tier = self.loops.entry(ins).or_insert_with(|| {
+            if compilable(ins) {
+                Tier::Interpreted(0)
+            } else {
+                Tier::Ineligible
+            }
+        });
+
+        match tier {
+            Tier::Interpreted(iterations) if *iterations >= threshold => {
+                let compiled = Jit::new()
+                    .assume_zeroed_tape(false)
+                    .set_io_mode(self.tiered.io_mode)
+                    .compile(&vec![ins.clone()]);
+
+                // Loops that can't be compiled or move too far for the guards keep running on
+                // the interpreter.
+                let Some(function) = compiled
+                    .ok()
+                    .filter(|function| function.reach() <= GUARDED_REACH)
+                else {
+                    *tier = Tier::Ineligible;
+                    return None;
+                };
+
+                let function = Rc::new(function);
+                *tier = Tier::Compiled(function.clone());
+                self.compiled += 1;
+                Some(function)
+            }
+            Tier::Interpreted(iterations) => {
+                *iterations += 1;
+                None
+            }
+            Tier::Compiled(function) => Some(function.clone()),
+            Tier::Ineligible => None,
+        }
+    }
+
+    /// Runs a compiled loop from cell `ptr` and returns the cell it ended on, or `None` if it
+    /// ended outside the tape.
+    fn run_compiled(&mut self, function: &Function, tape: &mut [u8], ptr: usize) -> Option<usize> {
+        // The tape is guarded for the function's reach, see `hot_loop`.
+        let base = tape.as_mut_ptr();
+        let end = unsafe { function.run_in(base.add(ptr), self.context) };
+        let ptr = (end as usize).wrapping_sub(base as usize);
+
+        (ptr < tape.len()).then_some(ptr)
+    }
+}
+
+/// Whether an instruction can run as compiled code on the interpreter's tape.
+fn compilable(ins: &Instruction) -> bool {
+    match ins {
+        Loop(body) => body.iter().all(compilable),
+        BalancedLoop(body) => body.iter().all(|(_, ins)| compilable(ins)),
+        Procedure(_) | Call | End | Store | Load | And | Or | Xor => false,
+        _ => true,
+    }
+}
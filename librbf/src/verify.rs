@@ -0,0 +1,194 @@
+use std::error::Error;
+use std::fmt;
+
+use crate::ast::Program;
+use crate::jit::{CompileError, Jit};
+use crate::opt::{MAX_LEVEL, PassManager};
+use crate::parser::Extensions;
+use crate::reference::{Execution, Reference, ReferenceError};
+use crate::runtime::Context;
+use crate::tiered::Tiered;
+
+/// What runs the optimized program in a [`Verifier`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Engine {
+    /// Native code compiled by [`Jit`].
+    Jit,
+    /// The [`Tiered`] interpreter, which compiles loops once they ran `threshold` iterations.
+    Tiered { threshold: u64 },
+}
+
+/// Checks that optimizing and compiling a program doesn't change what it does.
+///
+/// The source is parsed and run on the [`Reference`] interpreter, then optimized and run by an
+/// [`Engine`] with the same input. Both have to write the same bytes and leave the same tape
+/// and data pointer behind. Programs the reference can't run to their end, e.g. because they
+/// never end, are rejected before they reach the engine.
+#[derive(Clone, Debug)]
+pub struct Verifier {
+    reference: Reference,
+    extensions: Extensions,
+    passes: PassManager,
+    engine: Engine,
+}
+
+/// Why [`verify`] failed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VerifyError {
+    /// The reference interpreter couldn't run the program, so there's nothing to compare with.
+    Reference(ReferenceError),
+    /// The engine couldn't compile the optimized program.
+    Compile(CompileError),
+    /// The optimized program did something else than the reference.
+    Mismatch {
+        expected: Execution,
+        actual: Execution,
+    },
+    /// The optimized program moved the data pointer off the tape, which the reference never
+    /// lets a program do.
+    LeftTape { expected: Execution },
+}
+
+/// Verifies `source` with `input` on the JIT with all optimizations, see [`Verifier`].
+///
+/// Returns what the program did if the JIT agrees with the reference interpreter.
+pub fn verify(source: &[u8], input: &[u8]) -> Result<Execution, VerifyError> {
+    Verifier::new().verify(source, input)
+}
+
+impl Verifier {
+    /// Initializes a `Verifier` that checks the JIT with all optimizations on a tape of
+    /// `30_000` cells.
+    pub fn new() -> Verifier {
+        Verifier {
+            reference: Reference::new(),
+            extensions: Extensions::default(),
+            passes: PassManager::with_level(MAX_LEVEL).keep_final_tape(true),
+            engine: Engine::Jit,
+        }
+    }
+
+    /// Sets the reference interpreter, which also decides the tape size.
+    pub fn set_reference(mut self, reference: Reference) -> Self {
+        self.reference = reference;
+        self
+    }
+
+    /// Sets the extensions the source is parsed with.
+    pub fn set_extensions(mut self, extensions: Extensions) -> Self {
+        self.extensions = extensions;
+        self
+    }
+
+    /// Sets the passes that optimize the program before the engine runs it. They always keep
+    /// the final tape, since it's compared with the reference interpreter's.
+    pub fn set_passes(mut self, passes: PassManager) -> Self {
+        self.passes = passes.keep_final_tape(true);
+        self
+    }
+
+    /// Sets what runs the optimized program.
+    pub fn set_engine(mut self, engine: Engine) -> Self {
+        self.engine = engine;
+        self
+    }
+
+    /// Runs `source` with `input` on the reference interpreter and the engine, and returns
+    /// what the program did if both agree.
+    pub fn verify(&self, source: &[u8], input: &[u8]) -> Result<Execution, VerifyError> {
+        let program = self.extensions.parse(source);
+        let expected = self
+            .reference
+            .run(&program, input)
+            .map_err(VerifyError::Reference)?;
+        let Some(actual) = self
+            .execute(&self.passes.run(program), input)
+            .map_err(VerifyError::Compile)?
+        else {
+            return Err(VerifyError::LeftTape { expected });
+        };
+
+        if actual != expected {
+            return Err(VerifyError::Mismatch { expected, actual });
+        }
+
+        Ok(expected)
+    }
+
+    /// Runs `program` on the engine, or returns `None` if it moved the data pointer off the tape.
+    fn execute(&self, program: &Program, input: &[u8]) -> Result<Option<Execution>, CompileError> {
+        let mut tape = vec![0; self.reference.tape_size()];
+        let mut context = Context::captured(input);
+
+        let ptr = match self.engine {
+            Engine::Jit => {
+                let function = Jit::new().set_tape_size(tape.len()).compile(program)?;
+                function.run_guarded(&mut tape, 0, &mut context)
+            }
+            Engine::Tiered { threshold } => {
+                let tiered = Tiered::new().set_threshold(threshold);
+                tiered.run_on(program, &mut tape, 0, &mut context).0
+            }
+        };
+
+        Ok(ptr.map(|ptr| Execution {
+            output: context.into_output(),
+            tape,
+            ptr,
+        }))
+    }
+}
+
+impl Default for Verifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (expected, actual) = match self {
+            VerifyError::Reference(e) => return write!(f, "can't verify the program: {}", e),
+            VerifyError::Compile(e) => return write!(f, "can't compile the program: {}", e),
+            VerifyError::Mismatch { expected, actual } => (expected, actual),
+            VerifyError::LeftTape { expected } => {
+                return write!(
+                    f,
+                    "data pointer left the tape, expected it to end on cell {}",
+                    expected.ptr
+                );
+            }
+        };
+
+        if let Some(i) = first_difference(&expected.output, &actual.output) {
+            write!(
+                f,
+                "output differs at byte {}: expected {:?}, got {:?}",
+                i,
+                expected.output.get(i),
+                actual.output.get(i)
+            )
+        } else if let Some(i) = first_difference(&expected.tape, &actual.tape) {
+            write!(
+                f,
+                "tape differs at cell {}: expected {:?}, got {:?}",
+                i,
+                expected.tape.get(i),
+                actual.tape.get(i)
+            )
+        } else {
+            write!(
+                f,
+                "data pointer differs: expected cell {}, got cell {}",
+                expected.ptr, actual.ptr
+            )
+        }
+    }
+}
+
+impl Error for VerifyError {}
+
+/// The first index at which `a` and `b` differ, including where one of them ends.
+fn first_difference(a: &[u8], b: &[u8]) -> Option<usize> {
+    (0..a.len().max(b.len())).find(|&i| a.get(i) != b.get(i))
+}
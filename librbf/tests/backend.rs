@@ -0,0 +1,58 @@
+use librbf::{
+    Artifact, Backend, ExecutionOptions, MAX_LEVEL, PassManager, Tiered, backends, find_backend,
+    parse,
+};
+
+fn run(backend: &dyn Backend, source: &str, tape: &mut [u8], start: usize) -> usize {
+    let program = PassManager::with_level(MAX_LEVEL)
+        .assume_zeroed_tape(false)
+        .run(parse(source.as_bytes()));
+    let options = ExecutionOptions {
+        tape_size: tape.len(),
+        zeroed_tape: false,
+        ..ExecutionOptions::default()
+    };
+
+    match backend.compile(&program, &options).unwrap() {
+        Artifact::Runnable(runnable) => runnable.run_on(tape, start),
+        Artifact::Emitted(_) => panic!("{} doesn't run programs", backend.name()),
+    }
+}
+
+#[test]
+fn lists_the_host_jit_first() {
+    let names: Vec<_> = backends().iter().map(|backend| backend.name()).collect();
+
+    assert_eq!(names[0], format!("{}-jit", std::env::consts::ARCH));
+    assert!(names.contains(&"tiered"));
+}
+
+#[test]
+fn finds_backends_by_name() {
+    for backend in backends() {
+        assert_eq!(find_backend(backend.name()).unwrap().name(), backend.name());
+    }
+
+    assert!(find_backend("nope").is_none());
+}
+
+#[test]
+fn runs_programs_on_every_backend() {
+    for backend in backends() {
+        // Adds the start cell to the next one, then moves right to the first zero cell.
+        let mut tape = [9, 1, 2, 3, 0, 0];
+        let end = run(&*backend, "[->+<]>[>]", &mut tape, 1);
+
+        assert_eq!(end, 4, "{}", backend.name());
+        assert_eq!(tape, [9, 0, 3, 3, 0, 0], "{}", backend.name());
+    }
+}
+
+#[test]
+fn keeps_the_tiered_threshold() {
+    let mut tape = [0; 4];
+    let tiered = Tiered::new().set_threshold(0);
+
+    assert_eq!(run(&tiered, "+++[>+<-]>", &mut tape, 0), 1);
+    assert_eq!(tape, [0, 3, 0, 0]);
+}
@@ -0,0 +1,123 @@
+use std::fs;
+use std::path::PathBuf;
+
+use librbf::{Cache, CacheKey, Extensions, Instruction, Jit};
+
+fn cache_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("rbf-cache-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[test]
+fn loads_stored_code() {
+    let dir = cache_dir("load");
+    let cache = Cache::new(&dir);
+    let source = b"+(>++++[<++>-]<):";
+    let extensions = Extensions {
+        procedures: true,
+        ..Default::default()
+    };
+    let jit = Jit::new().set_tape_size(64);
+    let key = jit.cache_key(source);
+
+    assert!(cache.load(&key).unwrap().is_none());
+
+    let function = jit.compile(&extensions.parse(&source[..])).unwrap();
+    cache.store(&key, &function).unwrap();
+
+    let loaded = cache.load(&key).unwrap().expect("cached code");
+    let mut tape = [0; 64];
+    let end = unsafe { loaded.run_from(tape[8..].as_mut_ptr()) };
+
+    // Procedure 1 adds 8 to the cell it is called on.
+    assert_eq!(tape[8], 9);
+    assert_eq!(end, tape[8..].as_mut_ptr());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn keys_include_options() {
+    let source = b"+++.";
+
+    assert_eq!(Jit::new().cache_key(source), Jit::new().cache_key(source));
+    assert_ne!(Jit::new().cache_key(source), Jit::new().cache_key(b"++."));
+    assert_ne!(
+        Jit::new().cache_key(source),
+        Jit::new().set_tape_size(64).cache_key(source)
+    );
+    assert_ne!(
+        CacheKey::new(source).option("opt", true),
+        CacheKey::new(source).option("opt", false)
+    );
+}
+
+#[test]
+fn ignores_entries_of_other_sources_under_the_same_name() {
+    let dir = cache_dir("collision");
+    let cache = Cache::new(&dir);
+    let (key, other) = (Jit::new().cache_key(b"+."), Jit::new().cache_key(b"-."));
+    let function = Jit::new().compile(&vec![]).unwrap();
+
+    cache.store(&key, &function).unwrap();
+    let stored = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+    cache.store(&other, &function).unwrap();
+
+    // Give the entry for `other` the contents of the one for `key`, as if their names collided.
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        if path != stored {
+            fs::copy(&stored, &path).unwrap();
+        }
+    }
+
+    assert!(cache.load(&other).unwrap().is_none());
+    assert!(cache.load(&key).unwrap().is_some());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rejects_corrupt_entries() {
+    let dir = cache_dir("corrupt");
+    let cache = Cache::new(&dir);
+    let jit = Jit::new();
+    let key = jit.cache_key(b"+");
+
+    cache.store(&key, &jit.compile(&vec![]).unwrap()).unwrap();
+
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let bytes = fs::read(&path).unwrap();
+        fs::write(&path, &bytes[..bytes.len() / 2]).unwrap();
+    }
+
+    assert!(cache.load(&key).is_err());
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rejects_relocations_outside_the_code() {
+    let dir = cache_dir("relocation");
+    let cache = Cache::new(&dir);
+    let jit = Jit::new();
+    let key = jit.cache_key(b".");
+
+    let function = jit.compile(&vec![Instruction::Write]).unwrap();
+    cache.store(&key, &function).unwrap();
+
+    // The last relocation ends the file with its offset, target kind and index.
+    for entry in fs::read_dir(&dir).unwrap() {
+        let path = entry.unwrap().path();
+        let mut bytes = fs::read(&path).unwrap();
+        let offset = bytes.len() - 24;
+        bytes[offset..offset + 8].copy_from_slice(&(u64::MAX - 2).to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+    }
+
+    assert!(cache.load(&key).is_err());
+
+    let _ = fs::remove_dir_all(&dir);
+}
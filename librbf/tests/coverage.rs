@@ -0,0 +1,112 @@
+extern crate librbf;
+
+use librbf::{Coverage, Region, Span};
+
+fn coverage(source: &str) -> Coverage {
+    let (program, map) = librbf::parse_with_spans(source.as_bytes());
+    Coverage::collect(&program, &map, 64)
+}
+
+fn lcov(source: &str) -> String {
+    let mut out = Vec::new();
+    coverage(source)
+        .write_lcov(&mut out, "test.bf", source.as_bytes())
+        .unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+fn listing(source: &str) -> String {
+    let mut out = Vec::new();
+    coverage(source)
+        .write_listing(&mut out, source.as_bytes())
+        .unwrap();
+    String::from_utf8(out).unwrap()
+}
+
+#[test]
+fn maps_spans_to_instruction_runs() {
+    let (_, map) = librbf::parse_with_spans(" ++ [>-] ".as_bytes());
+
+    assert_eq!(map[0].span, Span { start: 1, end: 3 });
+    assert_eq!(map[1].span, Span { start: 4, end: 8 });
+    assert_eq!(map[1].body[0].span, Span { start: 5, end: 6 });
+    assert_eq!(map[1].body[1].span, Span { start: 6, end: 7 });
+}
+
+#[test]
+fn counts_loop_iterations() {
+    assert_eq!(
+        coverage("+++[-]").regions(),
+        [
+            Region {
+                span: Span { start: 0, end: 3 },
+                hits: 1,
+                iterations: None,
+            },
+            Region {
+                span: Span { start: 3, end: 6 },
+                hits: 1,
+                iterations: Some(3),
+            },
+            Region {
+                span: Span { start: 4, end: 5 },
+                hits: 3,
+                iterations: None,
+            },
+        ]
+    );
+}
+
+#[test]
+fn reports_never_entered_loops_as_branches() {
+    let report = lcov("[-]\n+");
+
+    assert!(report.contains("BRDA:1,0,0,0\nBRDA:1,0,1,1\n"));
+    assert!(report.contains("BRF:2\nBRH:1\n"));
+    assert!(report.contains("DA:1,1\nDA:2,1\nLF:2\nLH:2\n"));
+}
+
+#[test]
+fn reports_unreached_loops_without_branch_counts() {
+    let report = lcov("[[-]]");
+
+    assert!(report.contains("BRDA:1,1,0,-\nBRDA:1,1,1,-\n"));
+}
+
+#[test]
+fn annotates_listing_with_counts() {
+    assert_eq!(
+        listing("comment\n++[-]\n[>]\n"),
+        concat!(
+            "        -:    1:comment\n",
+            "        2:    2:++[-]\n",
+            "        1:    3:[>]\n",
+            "                 ^\n",
+        )
+    );
+}
+
+#[test]
+fn marks_unexecuted_lines() {
+    assert_eq!(
+        listing("[\n-\n]"),
+        concat!(
+            "        1:    1:[\n",
+            "    #####:    2:-\n",
+            "        -:    3:]\n",
+        )
+    );
+}
+
+#[test]
+fn marks_closing_brackets_on_later_lines() {
+    assert_eq!(
+        listing("[[\n]]"),
+        concat!(
+            "        1:    1:[[\n",
+            "                 ^\n",
+            "        -:    2:]]\n",
+            "                ^\n",
+        )
+    );
+}
@@ -0,0 +1,78 @@
+extern crate librbf;
+
+use librbf::{Dialect, DialectError, Instruction::*, Span};
+
+#[test]
+fn translates_ook() {
+    let source = b"Ook. Ook? Ook. Ook.\nOok! Ook? Ook! Ook! Ook? Ook!  Ook! Ook.";
+    assert_eq!(Dialect::ook().translate(source), b">+[-].");
+}
+
+#[test]
+fn translates_blub() {
+    assert_eq!(Dialect::blub().translate(b"Blub. Blub! Blub? Blub."), b",<");
+}
+
+#[test]
+fn translates_alphuck() {
+    assert_eq!(Dialect::alphuck().translate(b"eeapica sj"), b"++>[-<>].");
+}
+
+#[test]
+fn treats_unknown_bytes_as_comments() {
+    assert_eq!(Dialect::ook().translate(b"Ook. hello Ook.Ook. Ook."), b"+");
+}
+
+#[test]
+fn parses_dialect_source_into_programs() {
+    assert_eq!(
+        Dialect::alphuck().parse(b"eeepais"),
+        [Add(3), Loop(vec![Move(1), Add(-1)])]
+    );
+}
+
+#[test]
+fn maps_spans_to_dialect_tokens() {
+    let (_, map) = Dialect::ook().parse_with_spans(b"Ook. Ook. Ook. Ook.  Ook! Ook?Ook? Ook!");
+
+    assert_eq!(map[0].span, Span { start: 0, end: 19 });
+    assert_eq!(map[1].span, Span { start: 21, end: 39 });
+}
+
+#[test]
+fn reads_mapping_files() {
+    let dialect = Dialect::from_mapping(
+        "# Words\n\n+ inc\n- dec\n> go right\n< go left\n. out\n, in\n[ while\n] end\n",
+    )
+    .unwrap();
+
+    assert_eq!(
+        dialect.translate(b"inc while go  right in end out go left dec"),
+        b"+[>,].<-"
+    );
+}
+
+#[test]
+fn prefers_longer_tokens() {
+    let dialect = Dialect::from_mapping("+ a\n- aa\n").unwrap();
+    assert_eq!(dialect.translate(b"aaa"), b"-+");
+}
+
+#[test]
+fn rejects_unknown_commands() {
+    assert_eq!(
+        Dialect::from_mapping("+ inc\n* mul\n"),
+        Err(DialectError::UnknownCommand {
+            line: 2,
+            command: "*".to_string()
+        })
+    );
+}
+
+#[test]
+fn rejects_missing_tokens() {
+    assert_eq!(
+        Dialect::from_mapping("+\n"),
+        Err(DialectError::MissingToken { line: 1 })
+    );
+}
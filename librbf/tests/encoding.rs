@@ -0,0 +1,78 @@
+use librbf::{DecodeError, Instruction::*, Program, decode, encode};
+
+fn every_instruction() -> Program {
+    vec![
+        Add(3),
+        Move(-70),
+        Set(255),
+        Mul(-2, 7),
+        MulRun(vec![(1, 4), (-3, -1)]),
+        Scan(i64::MIN),
+        Read,
+        Write,
+        WriteConst(10),
+        WriteBytes(b"hi\n".to_vec()),
+        Loop(vec![Add(-1), Loop(vec![])]),
+        BalancedLoop(vec![(2, Write), (-1, BalancedLoop(vec![(0, Add(-1))]))]),
+        Procedure(vec![Move(i64::MAX)]),
+        Call,
+        End,
+        Store,
+        Load,
+        Shift(-3),
+        Not,
+        And,
+        Or,
+        Xor,
+    ]
+}
+
+#[test]
+fn round_trips_every_instruction() {
+    let program = every_instruction();
+
+    assert_eq!(decode(&encode(&program)), Ok(program));
+}
+
+#[test]
+fn encodes_compactly() {
+    assert_eq!(encode(&vec![]), [1, 0]);
+    assert_eq!(
+        encode(&vec![Add(-1), Loop(vec![Move(64)]), Write]),
+        [1, 3, 0, 1, 10, 1, 1, 0x80, 0x01, 7]
+    );
+}
+
+#[test]
+fn rejects_invalid_input() {
+    assert_eq!(decode(&[]), Err(DecodeError::UnexpectedEnd));
+    assert_eq!(decode(&[2, 0]), Err(DecodeError::UnsupportedVersion(2)));
+    assert_eq!(decode(&[1, 1, 22]), Err(DecodeError::UnknownTag(22)));
+    assert_eq!(decode(&[1, 2, 6]), Err(DecodeError::UnexpectedEnd));
+    assert_eq!(decode(&[1, 1, 9, 5, b'a']), Err(DecodeError::UnexpectedEnd));
+    assert_eq!(decode(&[1, 1, 9, 0]), Err(DecodeError::EmptyWrite));
+    assert_eq!(decode(&[1, 0, 6]), Err(DecodeError::TrailingBytes));
+
+    let mut too_large = vec![1, 1, 0];
+    too_large.extend([0xFF; 9]);
+    too_large.push(0x7F);
+    assert_eq!(decode(&too_large), Err(DecodeError::Overflow));
+}
+
+#[test]
+fn rejects_deeply_nested_loops() {
+    // 200,000 loops, each holding the next one.
+    let mut nested = vec![1];
+    for _ in 0..200_000 {
+        nested.extend([1, 10]);
+    }
+    nested.push(0);
+
+    assert_eq!(decode(&nested), Err(DecodeError::TooDeep));
+
+    let mut program = vec![];
+    for _ in 0..100 {
+        program = vec![Loop(program)];
+    }
+    assert_eq!(decode(&encode(&program)), Ok(program));
+}
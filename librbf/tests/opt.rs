@@ -1,9 +1,15 @@
 extern crate librbf;
 
-use librbf::{Instruction::*, Program};
+use librbf::{Instruction::*, Pass, PassManager, Program};
+
+/// All passes, except for those that would evaluate most of these programs away because they
+/// start on a zeroed tape.
+fn all_passes() -> PassManager {
+    PassManager::with_level(librbf::MAX_LEVEL).assume_zeroed_tape(false)
+}
 
 fn opt(input: &str) -> Program {
-    librbf::optimize(librbf::parse(input.as_bytes()))
+    all_passes().run(librbf::parse(input.as_bytes()))
 }
 
 #[test]
@@ -28,17 +34,42 @@ fn groups_consecutive_moves_with_comments() {
 
 #[test]
 fn preserves_non_optimizable_nested_loops() {
+    // The second inner loop always starts on a zero cell, so it is removed.
     let expected = vec![
         Add(-1),
-        Loop(vec![Add(2), Loop(vec![Add(-2)]), Loop(vec![Add(2)])]),
-        Add(1),
+        BalancedLoop(vec![(0, Add(2)), (0, BalancedLoop(vec![(0, Add(-2))]))]),
+        Set(1),
     ];
     assert_eq!(opt("-[++[--][++]]+"), expected);
 }
 
+#[test]
+fn preserves_non_optimizable_nested_loops_around_input() {
+    let expected = vec![
+        Add(-1),
+        BalancedLoop(vec![
+            (0, Add(2)),
+            (0, BalancedLoop(vec![(0, Add(-2))])),
+            (0, Read),
+            (0, BalancedLoop(vec![(0, Add(2))])),
+        ]),
+        Set(1),
+    ];
+    assert_eq!(opt("-[++[--],[++]]+"), expected);
+}
+
 #[test]
 fn preserves_empty_loops() {
-    assert_eq!(opt("++[][]+"), [Add(2), Loop(vec![]), Loop(vec![]), Add(1)]);
+    // The second loop always starts on a zero cell, so it is removed.
+    assert_eq!(opt("++[][]+"), [Add(2), Loop(vec![]), Set(1)]);
+}
+
+#[test]
+fn preserves_empty_loops_around_input() {
+    assert_eq!(
+        opt("++[],[]+"),
+        [Add(2), Loop(vec![]), Read, Loop(vec![]), Set(1)]
+    );
 }
 
 #[test]
@@ -66,6 +97,12 @@ fn transforms_a_clear_loop_into_a_set() {
     assert_eq!(opt("[-]"), [Set(0)]);
 }
 
+#[test]
+fn transforms_clear_loops_with_odd_steps_into_sets() {
+    assert_eq!(opt("[+]"), [Set(0)]);
+    assert_eq!(opt("[---]"), [Set(0)]);
+}
+
 #[test]
 fn combines_set_with_following_adds() {
     assert_eq!(opt("[-]+++"), [Set(3)]);
@@ -101,7 +138,287 @@ fn transforms_multiplication_loops_into_mul_runs() {
     assert_eq!(opt("[>+<-]"), [MulRun(vec![(1, 1)])]);
 }
 
+#[test]
+fn scales_mul_runs_by_the_inverse_of_odd_steps() {
+    // 3 * 171 = 513 = 1 (mod 256), so each iteration of `---` moves the cell 1/3 of the way.
+    assert_eq!(opt("[--->+<]"), [MulRun(vec![(1, -85)])]);
+    assert_eq!(opt("[+>++<]"), [MulRun(vec![(1, -2)])]);
+    assert_eq!(opt("[+++>+++<]"), [MulRun(vec![(1, -1)])]);
+}
+
+#[test]
+fn preserves_multiplication_loops_with_even_steps() {
+    assert_eq!(
+        opt("[-->+<]"),
+        [BalancedLoop(vec![(0, Add(-2)), (1, Add(1))])]
+    );
+}
+
+#[test]
+fn addresses_balanced_loop_bodies_by_offset() {
+    assert_eq!(
+        opt("[>.<-]"),
+        [BalancedLoop(vec![(1, Write), (0, Add(-1))])]
+    );
+    assert_eq!(
+        opt("[>[>>,<<-]<-]"),
+        [BalancedLoop(vec![
+            (1, BalancedLoop(vec![(2, Read), (0, Add(-1))])),
+            (0, Add(-1)),
+        ])]
+    );
+}
+
+#[test]
+fn preserves_loops_that_move_the_data_pointer() {
+    assert_eq!(opt("[>.]"), [Loop(vec![Move(1), Write])]);
+    assert_eq!(opt("[-[>]<]"), [Loop(vec![Add(-1), Scan(1), Move(-1)])]);
+}
+
 #[test]
 fn orders_mul_run_offsets() {
     assert_eq!(opt("[>+++>++<<-]"), [MulRun(vec![(1, 3), (2, 2)])]);
 }
+
+#[test]
+fn optimizes_procedure_bodies() {
+    let extensions = librbf::Extensions {
+        procedures: true,
+        ..Default::default()
+    };
+    let program = all_passes().run(extensions.parse("(++[-]+):+:".as_bytes()));
+
+    assert_eq!(program, [Procedure(vec![Set(1)]), Call, Add(1), Call]);
+}
+
+fn opt_extended(input: &str) -> Program {
+    let extensions = librbf::Extensions {
+        extended: true,
+        ..Default::default()
+    };
+    all_passes().run(extensions.parse(input.as_bytes()))
+}
+
+#[test]
+fn omits_instructions_after_end() {
+    assert_eq!(opt_extended("+@+.[-]"), [Add(1), End]);
+}
+
+#[test]
+fn groups_shifts_in_the_same_direction() {
+    assert_eq!(opt_extended("{{ {}}"), [Shift(3), Shift(-2)]);
+}
+
+#[test]
+fn transforms_long_shifts_into_sets() {
+    assert_eq!(opt_extended("+{{{{{{{{"), [Set(0)]);
+}
+
+#[test]
+fn folds_known_values_through_bitwise_instructions() {
+    assert_eq!(opt_extended("[-]+++{}~"), [Set(252)]);
+}
+
+#[test]
+fn folds_bitwise_instructions_after_store() {
+    assert_eq!(opt_extended("$!&|^"), [Store, Set(0)]);
+}
+
+#[test]
+fn evaluates_programs_without_input_at_compile_time() {
+    let program = librbf::parse("++++++++[>++++++++<-]>+.+.".as_bytes());
+
+    assert_eq!(librbf::optimize(program), [WriteBytes(b"AB".to_vec())]);
+}
+
+#[test]
+fn keeps_the_final_tape_of_evaluated_programs_on_request() {
+    let program = librbf::parse("++++++++[>++++++++<-]>+.+.".as_bytes());
+    let passes = PassManager::with_level(librbf::MAX_LEVEL).keep_final_tape(true);
+
+    assert_eq!(passes.run(program), [Move(1), WriteBytes(b"AB".to_vec())]);
+}
+
+#[test]
+fn evaluates_programs_up_to_the_first_read() {
+    let program = librbf::parse("++++++++[>++++++++<-]>+.,.".as_bytes());
+
+    assert_eq!(
+        librbf::optimize(program),
+        [Move(1), WriteConst(65), Read, Write]
+    );
+}
+
+#[test]
+fn continues_at_loops_that_read_input() {
+    let program = librbf::parse("+++>++.[<.,]".as_bytes());
+
+    assert_eq!(
+        librbf::optimize(program),
+        [
+            Set(3),
+            Move(1),
+            WriteConst(2),
+            Loop(vec![Move(-1), Write, Read])
+        ]
+    );
+}
+
+#[test]
+fn gives_up_on_loops_that_run_too_long() {
+    assert_eq!(
+        librbf::optimize(librbf::parse("+[]".as_bytes())),
+        [Set(1), Loop(vec![])]
+    );
+}
+
+/// All passes except for partial evaluation and dead-store elimination, which would leave
+/// little of the propagated values.
+fn const_prop(input: &str) -> Program {
+    let manager = PassManager::with_level(librbf::MAX_LEVEL)
+        .disable_pass(Pass::PartialEval)
+        .disable_pass(Pass::DeadStore);
+
+    opt_with(manager, input)
+}
+
+#[test]
+fn removes_comment_loops_at_the_start() {
+    assert_eq!(const_prop("[a comment, with. commands]+."), [WriteConst(1)]);
+}
+
+#[test]
+fn propagates_known_cells_past_input() {
+    assert_eq!(
+        const_prop(",>[-]+<[>+<-]>."),
+        [
+            Read,
+            Move(1),
+            Set(1),
+            Move(-1),
+            MulRun(vec![(1, 1)]),
+            Move(1),
+            Write
+        ]
+    );
+}
+
+#[test]
+fn propagates_known_cells_into_transfers() {
+    assert_eq!(
+        const_prop(">+++<,>[>++<-]>."),
+        [
+            Move(1),
+            Set(3),
+            Move(-1),
+            Read,
+            Move(2),
+            Set(6),
+            Move(-1),
+            Set(0),
+            Move(1),
+            WriteConst(6)
+        ]
+    );
+}
+
+#[test]
+fn keeps_loops_at_the_start_on_a_tape_with_data() {
+    assert_eq!(opt("[>+<-]+"), [MulRun(vec![(1, 1)]), Set(1)]);
+}
+
+#[test]
+fn removes_stores_overwritten_across_moves() {
+    assert_eq!(
+        opt_with(PassManager::with_level(2), "+>+<[-]"),
+        [Move(1), Add(1), Move(-1), Set(0)]
+    );
+}
+
+#[test]
+fn removes_stores_before_reads() {
+    assert_eq!(
+        opt_with(PassManager::with_level(2), "[-]+++>+<,"),
+        [Move(1), Add(1), Move(-1), Read]
+    );
+}
+
+#[test]
+fn keeps_stores_that_loops_or_later_code_may_read() {
+    assert_eq!(
+        opt_with(PassManager::with_level(2), "+>+<[.-]>+"),
+        [
+            Add(1),
+            Move(1),
+            Add(1),
+            Move(-1),
+            Loop(vec![Write, Add(-1)]),
+            Move(1),
+            Add(1)
+        ]
+    );
+}
+
+fn opt_with(manager: PassManager, input: &str) -> Program {
+    manager.run(librbf::parse(input.as_bytes()))
+}
+
+#[test]
+fn runs_no_passes_at_level_0() {
+    assert_eq!(PassManager::with_level(0).passes(), []);
+    assert_eq!(
+        opt_with(PassManager::with_level(0), "+-+[-]"),
+        [Add(1), Add(-1), Add(1), Loop(vec![Add(-1)])]
+    );
+}
+
+#[test]
+fn adds_passes_with_each_level() {
+    assert_eq!(
+        opt_with(PassManager::with_level(1), "+-+[-]"),
+        [Add(1), Loop(vec![Add(-1)])]
+    );
+    assert_eq!(
+        opt_with(PassManager::with_level(2), "++[-]."),
+        [Set(0), Write]
+    );
+    assert_eq!(
+        opt_with(PassManager::with_level(3), "++[-]."),
+        [WriteConst(0)]
+    );
+}
+
+#[test]
+fn runs_only_selected_passes() {
+    let manager = PassManager::new().add_pass(Pass::MulRun);
+
+    assert_eq!(opt_with(manager, "+[>+<-]"), [Add(1), MulRun(vec![(1, 1)])]);
+}
+
+#[test]
+fn skips_disabled_passes() {
+    let manager = PassManager::with_level(2).disable_pass(Pass::MulRun);
+
+    assert_eq!(
+        opt_with(manager, "[>+<-]"),
+        [Loop(vec![Move(1), Add(1), Move(-1), Add(-1)])]
+    );
+}
+
+#[test]
+fn runs_balanced_loop_last() {
+    let manager = PassManager::new()
+        .add_pass(Pass::BalancedLoop)
+        .add_pass(Pass::MulRun);
+
+    assert_eq!(opt_with(manager, "[>+<-]"), [MulRun(vec![(1, 1)])]);
+}
+
+#[test]
+fn parses_pass_names() {
+    for pass in Pass::ALL {
+        assert_eq!(pass.name().parse(), Ok(pass));
+    }
+
+    assert!("bogus".parse::<Pass>().is_err());
+}
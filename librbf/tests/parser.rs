@@ -92,3 +92,34 @@ fn preserves_nested_empty_loops() {
         ]
     );
 }
+
+#[test]
+fn ignores_procedure_commands_by_default() {
+    assert_eq!(parse("+(-):"), [Add(1), Add(-1)]);
+}
+
+#[test]
+fn parses_procedures_with_pbrain_extension() {
+    let extensions = librbf::Extensions {
+        procedures: true,
+        ..Default::default()
+    };
+
+    assert_eq!(
+        extensions.parse("+(-[.]) :".as_bytes()),
+        [Add(1), Procedure(vec![Add(-1), Loop(vec![Write])]), Call]
+    );
+}
+
+#[test]
+fn parses_extended_type_1_instructions() {
+    let extensions = librbf::Extensions {
+        extended: true,
+        ..Default::default()
+    };
+
+    assert_eq!(
+        extensions.parse("$!{{}~&|^@".as_bytes()),
+        [Store, Load, Shift(2), Shift(-1), Not, And, Or, Xor, End]
+    );
+}
@@ -0,0 +1,42 @@
+#![cfg(feature = "serde")]
+
+use librbf::{Instruction::*, Program};
+
+#[test]
+fn serializes_to_tagged_json() {
+    let program = vec![
+        Add(3),
+        Mul(1, -2),
+        MulRun(vec![(1, 4)]),
+        Read,
+        WriteBytes(b"A".to_vec()),
+        Loop(vec![Move(1)]),
+    ];
+
+    assert_eq!(
+        serde_json::to_string(&program).unwrap(),
+        concat!(
+            r#"[{"op":"add","args":3},{"op":"mul","args":[1,-2]},"#,
+            r#"{"op":"mul_run","args":[[1,4]]},{"op":"read"},"#,
+            r#"{"op":"write_bytes","args":[65]},{"op":"loop","args":[{"op":"move","args":1}]}]"#
+        )
+    );
+}
+
+#[test]
+fn serializes_balanced_loops_as_offset_pairs() {
+    let program = vec![BalancedLoop(vec![(1, Write), (0, Add(-1))])];
+
+    assert_eq!(
+        serde_json::to_string(&program).unwrap(),
+        r#"[{"op":"balanced_loop","args":[[1,{"op":"write"}],[0,{"op":"add","args":-1}]]}]"#
+    );
+}
+
+#[test]
+fn deserializes_json() {
+    let json = r#"[{"op":"set","args":0},{"op":"procedure","args":[{"op":"call"}]},{"op":"end"}]"#;
+    let program: Program = serde_json::from_str(json).unwrap();
+
+    assert_eq!(program, [Set(0), Procedure(vec![Call]), End]);
+}
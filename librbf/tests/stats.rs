@@ -0,0 +1,71 @@
+use librbf::{LoopReason, PassManager, Stats, parse};
+
+const SOURCE: &[u8] = b"++++[>++<-]>[.>]<[-]+[,.]+[>+<--][>>][->>>]";
+
+#[test]
+fn counts_source_and_optimized_instructions() {
+    // Evaluating the start of the program at compile time would leave little to count.
+    let manager = PassManager::with_level(3).assume_zeroed_tape(false);
+    let program = parse(SOURCE);
+    let stats = Stats::collect(&program, &manager);
+
+    assert_eq!(stats.source_commands, 43);
+    assert_eq!(stats.parsed_instructions, 28);
+    assert_eq!(stats.optimized_instructions(), 15);
+    assert_eq!(stats.instructions["MulRun"], 1);
+    assert_eq!(stats.instructions["Loop"], 1);
+    assert_eq!(stats.instructions["BalancedLoop"], 2);
+    assert_eq!(
+        stats.optimized_instructions(),
+        Stats::collect(&manager.run(program), &PassManager::new()).parsed_instructions
+    );
+}
+
+#[test]
+fn explains_what_loops_became() {
+    let stats = Stats::collect(&parse(SOURCE), &PassManager::with_level(3));
+    let loops = &stats.loops;
+
+    assert_eq!(loops.total(), 7);
+    assert_eq!(
+        (
+            loops.set,
+            loops.scan,
+            loops.mul_run,
+            loops.balanced,
+            loops.other
+        ),
+        (1, 1, 1, 2, 0)
+    );
+    assert_eq!(
+        loops.kept.iter().map(|(&r, &n)| (r, n)).collect::<Vec<_>>(),
+        [(LoopReason::Io, 1), (LoopReason::Unbalanced, 1)]
+    );
+}
+
+#[test]
+fn explains_why_loops_stayed_loops() {
+    let stats = Stats::collect(&parse(SOURCE), &PassManager::with_level(2));
+
+    assert_eq!(
+        stats
+            .loops
+            .kept
+            .iter()
+            .map(|(&r, &n)| (r, n))
+            .collect::<Vec<_>>(),
+        [
+            (LoopReason::Io, 2),
+            (LoopReason::Unbalanced, 1),
+            (LoopReason::Step, 1)
+        ]
+    );
+}
+
+#[test]
+fn keeps_all_loops_without_optimization() {
+    let stats = Stats::collect(&parse(SOURCE), &PassManager::new());
+
+    assert_eq!(stats.optimized_instructions(), stats.parsed_instructions);
+    assert_eq!(stats.loops.kept.values().sum::<usize>(), 7);
+}
@@ -0,0 +1,59 @@
+use librbf::{Extensions, MAX_LEVEL, PassManager, Program, Tiered, parse};
+
+const NESTED: &[u8] = b"++++++++[>++++++++[>+<-]<-]";
+
+/// Optimizes the loops of `source`. On a zeroed tape, the whole program would be evaluated at
+/// compile time.
+fn optimize_loops(source: &[u8]) -> Program {
+    PassManager::with_level(MAX_LEVEL)
+        .assume_zeroed_tape(false)
+        .run(parse(source))
+}
+
+#[test]
+fn compiles_hot_loops() {
+    let program = parse(NESTED);
+
+    // Only the inner loop runs more than 10 iterations in total.
+    assert_eq!(Tiered::new().set_threshold(10).run(&program), 1);
+    assert_eq!(Tiered::new().set_threshold(4).run(&program), 2);
+    // With no threshold the outer loop is compiled on its first iteration.
+    assert_eq!(Tiered::new().set_threshold(0).run(&program), 1);
+}
+
+#[test]
+fn interprets_cold_loops() {
+    let program = optimize_loops(NESTED);
+
+    assert_eq!(Tiered::new().run(&program), 0);
+}
+
+#[test]
+fn compiles_hot_balanced_loops() {
+    // The outer loop becomes a balanced loop around the inner loop's `MulRun`.
+    let program = optimize_loops(NESTED);
+
+    assert_eq!(Tiered::new().set_threshold(4).run(&program), 1);
+}
+
+#[test]
+fn interprets_loops_that_move_too_far_for_the_guards() {
+    use librbf::Instruction::{Add, Loop, Move};
+
+    let program = vec![Add(8), Loop(vec![Add(-1), Move(1 << 21), Move(-(1 << 21))])];
+
+    let tiered = Tiered::new().set_tape_size((1 << 21) + 1);
+
+    assert_eq!(tiered.set_threshold(0).run(&program), 0);
+}
+
+#[test]
+fn interprets_loops_using_procedures() {
+    let extensions = Extensions {
+        procedures: true,
+        ..Default::default()
+    };
+    let program = extensions.parse(&b"+(-)[:]"[..]);
+
+    assert_eq!(Tiered::new().set_threshold(0).run(&program), 0);
+}
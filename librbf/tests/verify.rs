@@ -0,0 +1,185 @@
+use librbf::{Engine, Execution, Reference, ReferenceError, Verifier, VerifyError, parse, verify};
+
+/// A xorshift generator, so failing programs can be reproduced from their seed.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+/// Generates a random program with balanced brackets. It starts a few cells into the tape and
+/// moves to the right are more likely, so most programs stay on the tape.
+fn random_program(rng: &mut Rng) -> Vec<u8> {
+    let mut source = b">>>>>>>>".to_vec();
+    let mut depth = 0;
+
+    for _ in 0..rng.below(60) {
+        let byte = match rng.below(20) {
+            0..4 => b'+',
+            4..8 => b'-',
+            8..11 => b'>',
+            11..13 => b'<',
+            13 => b'.',
+            14 => b',',
+            15..17 => b'[',
+            17..19 if depth > 0 => b']',
+            _ => b'-',
+        };
+
+        match byte {
+            b'[' => depth += 1,
+            b']' => depth -= 1,
+            _ => {}
+        }
+
+        source.push(byte);
+    }
+
+    source.extend(std::iter::repeat_n(b']', depth));
+    source
+}
+
+fn check_random_programs(verifier: &Verifier) {
+    let mut verified = 0;
+
+    for seed in 1..=500 {
+        let mut rng = Rng(seed);
+        let source = random_program(&mut rng);
+        let input: Vec<u8> = (0..rng.below(8)).map(|_| rng.next() as u8).collect();
+
+        match verifier.verify(&source, &input) {
+            Ok(_) => verified += 1,
+            Err(VerifyError::Reference(_)) => {}
+            Err(e) => panic!(
+                "seed {}: {} for {:?}",
+                seed,
+                e,
+                String::from_utf8_lossy(&source)
+            ),
+        }
+    }
+
+    // Make sure the generator doesn't only produce programs the reference rejects.
+    assert!(verified > 250, "only {} programs were verified", verified);
+}
+
+fn reference() -> Reference {
+    Reference::new().set_tape_size(256).set_max_steps(100_000)
+}
+
+#[test]
+fn reference_runs_programs() {
+    let program = parse("++++++++[>++++++++<-]>+.+.".as_bytes());
+    let execution = reference().run(&program, &[]).unwrap();
+
+    assert_eq!(execution.output, b"AB");
+    assert_eq!(execution.tape[..2], [0, 66]);
+    assert_eq!(execution.ptr, 1);
+}
+
+#[test]
+fn reference_reads_zero_past_the_end_of_the_input() {
+    let program = parse(",>,>,".as_bytes());
+    let execution = reference().run(&program, b"a").unwrap();
+
+    assert_eq!(execution.tape[..3], [b'a', 0, 0]);
+}
+
+#[test]
+fn reference_rejects_programs_it_cant_run() {
+    let run = |source: &str| reference().run(&parse(source.as_bytes()), &[]);
+
+    assert_eq!(run("+[]"), Err(ReferenceError::StepLimit));
+    assert_eq!(run("<"), Err(ReferenceError::OutOfBounds));
+    assert_eq!(run("+[>+]"), Err(ReferenceError::OutOfBounds));
+}
+
+#[test]
+fn verifies_cells_that_wrap_around() {
+    // Values of 255 and above used to be reduced modulo 255 instead of wrapping.
+    for source in ["-", ",[-]-", ",[-]-.", "+[-]-->-.", ",>-[<++>-]<."] {
+        let execution = verify(source.as_bytes(), b"\x01").unwrap();
+        assert!(execution.tape.contains(&255) || execution.output.contains(&255));
+    }
+
+    let mut source = b",[-]".to_vec();
+    source.extend([b'+'; 300]);
+    assert_eq!(verify(&source, b"\x01").unwrap().tape[0], 44);
+}
+
+#[test]
+fn verifies_random_programs_on_the_jit() {
+    check_random_programs(&Verifier::new().set_reference(reference()));
+}
+
+#[test]
+fn verifies_random_programs_on_the_tiered_interpreter() {
+    check_random_programs(
+        &Verifier::new()
+            .set_reference(reference())
+            .set_engine(Engine::Tiered { threshold: 2 }),
+    );
+}
+
+#[test]
+fn reports_the_first_difference() {
+    let expected = Execution {
+        output: b"ab".to_vec(),
+        tape: vec![0, 1],
+        ptr: 0,
+    };
+    let mismatch = |actual: Execution| {
+        VerifyError::Mismatch {
+            expected: expected.clone(),
+            actual,
+        }
+        .to_string()
+    };
+
+    assert_eq!(
+        mismatch(Execution {
+            output: b"a".to_vec(),
+            ..expected.clone()
+        }),
+        "output differs at byte 1: expected Some(98), got None"
+    );
+    assert_eq!(
+        mismatch(Execution {
+            tape: vec![0, 2],
+            ..expected.clone()
+        }),
+        "tape differs at cell 1: expected Some(1), got Some(2)"
+    );
+    assert_eq!(
+        mismatch(Execution {
+            ptr: 1,
+            ..expected.clone()
+        }),
+        "data pointer differs: expected cell 0, got cell 1"
+    );
+}
+
+#[test]
+fn reports_engines_that_leave_the_tape() {
+    let error = VerifyError::LeftTape {
+        expected: Execution {
+            output: vec![],
+            tape: vec![0, 0],
+            ptr: 1,
+        },
+    };
+
+    assert_eq!(
+        error.to_string(),
+        "data pointer left the tape, expected it to end on cell 1"
+    );
+}
@@ -1,15 +1,22 @@
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+mod repl;
 
-use clap::{ArgAction, Parser, ValueEnum};
-use librbf::{Jit, optimize, parse};
+use std::fs::{self, File};
+use std::io::{self, IsTerminal, Write};
+use std::path::{Path, PathBuf};
+
+use clap::error::ErrorKind;
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
+use librbf::{
+    Artifact, Backend, Cache, Coverage, Dialect, ExecutionOptions, Extensions, Flush, IoMode, Jit,
+    MAX_LEVEL, OutputFormat, Pass, PassManager, Program, Runnable, Stats, Tiered, backends,
+    find_backend,
+};
 
 #[derive(Parser)]
-#[command(version, about)]
+#[command(version, about, subcommand_negates_reqs = true)]
 struct Args {
-    #[arg(value_name = "PROGRAM", help = "The program")]
-    program: PathBuf,
+    #[arg(value_name = "PROGRAM", required = true, help = "The program")]
+    program: Option<PathBuf>,
 
     #[arg(
         short = 't',
@@ -19,6 +26,31 @@ struct Args {
     )]
     tape_size: usize,
 
+    #[arg(
+        long = "tape-init",
+        value_name = "FILE",
+        value_parser = parse_tape_init,
+        conflicts_with_all = ["coverage", "emit"],
+        help = "Fills the start of the tape from a file, or from hex digits after hex:"
+    )]
+    tape_init: Option<TapeInit>,
+
+    #[arg(
+        long = "start-at",
+        value_name = "CELL",
+        conflicts_with_all = ["coverage", "emit", "stats"],
+        help = "The cell the data pointer starts at [default: 0]"
+    )]
+    start_at: Option<usize>,
+
+    #[arg(
+        long = "dump-tape",
+        value_name = "FILE",
+        conflicts_with_all = ["coverage", "emit", "stats"],
+        help = "Writes the tape to a file after the program ends"
+    )]
+    dump_tape: Option<PathBuf>,
+
     #[arg(
         short,
         value_name = "EMIT",
@@ -26,31 +58,495 @@ struct Args {
     )]
     emit: Option<Emit>,
 
-    #[arg(long = "no-opt", action = ArgAction::SetFalse, help = "Disables optimization")]
+    #[arg(long = "no-opt", action = ArgAction::SetFalse, help = "Disables optimization, same as -O0")]
     opt: bool,
+
+    #[arg(
+        short = 'O',
+        value_name = "LEVEL",
+        default_value_t = MAX_LEVEL,
+        value_parser = clap::value_parser!(u8).range(0..=MAX_LEVEL as i64),
+        help = "The optimization level"
+    )]
+    level: u8,
+
+    #[arg(
+        long,
+        value_name = "PASSES",
+        value_delimiter = ',',
+        help = "Runs exactly these optimization passes in this order instead of an optimization level"
+    )]
+    passes: Option<Vec<Pass>>,
+
+    #[arg(
+        long = "disable-pass",
+        value_name = "PASS",
+        help = "Disables an optimization pass"
+    )]
+    disabled_passes: Vec<Pass>,
+
+    #[arg(
+        long,
+        value_name = "DIR",
+        help = "Runs the unoptimized program and writes lcov.info and an annotated listing to DIR"
+    )]
+    coverage: Option<PathBuf>,
+
+    #[arg(
+        long,
+        value_name = "DIALECT",
+        value_parser = parse_dialect,
+        conflicts_with = "extensions",
+        help = "Reads the program in a dialect: ook, blub, alphuck or a mapping file"
+    )]
+    dialect: Option<Dialect>,
+
+    #[arg(
+        short = 'x',
+        long = "extension",
+        value_name = "EXTENSION",
+        help = "Enables a language extension"
+    )]
+    extensions: Vec<Extension>,
+
+    #[arg(
+        long = "numeric-io",
+        conflicts_with = "coverage",
+        help = "Reads and writes cells as decimal numbers instead of bytes"
+    )]
+    numeric_io: bool,
+
+    #[arg(
+        long,
+        value_name = "WHEN",
+        help = "When output is written, defaults to line on a terminal and exit otherwise"
+    )]
+    flush: Option<FlushMode>,
+
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "raw",
+        help = "How written bytes are shown"
+    )]
+    output: Output,
+
+    #[arg(
+        long,
+        value_name = "BACKEND",
+        value_parser = parse_backend,
+        help = "Compiles the program with a backend, see the backends command"
+    )]
+    backend: Option<String>,
+
+    #[arg(
+        long,
+        conflicts_with = "backend",
+        help = "Starts on an interpreter and only compiles loops that run often, same as --backend tiered"
+    )]
+    tiered: bool,
+
+    #[arg(
+        long = "tier-threshold",
+        value_name = "ITERATIONS",
+        default_value_t = 1_000,
+        help = "Loop iterations before a loop is compiled with --tiered"
+    )]
+    tier_threshold: u64,
+
+    #[arg(
+        long = "cache-dir",
+        value_name = "DIR",
+        conflicts_with_all = ["emit", "backend", "tiered", "coverage"],
+        help = "Reuses compiled code from DIR and stores newly compiled code there"
+    )]
+    cache_dir: Option<PathBuf>,
+
+    #[arg(
+        long,
+        conflicts_with_all = ["emit", "backend", "tiered", "coverage", "cache_dir"],
+        help = "Shows optimization statistics and the generated code size instead of running the program"
+    )]
+    stats: bool,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Runs lines of code interactively on a tape that persists between lines
+    Repl,
+    /// Lists the backends available on this host
+    Backends,
 }
 
 #[derive(Clone, Debug, ValueEnum)]
 enum Emit {
+    /// The program's debug representation
     Ast,
+    /// The program as JSON
+    Json,
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum FlushMode {
+    /// After every byte
+    Always,
+    /// After every newline
+    Line,
+    /// When the buffer is full, before reading input and when the program ends
+    Exit,
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum Output {
+    /// The bytes themselves
+    Raw,
+    /// Every byte as the Latin-1 character with that code point, encoded as UTF-8
+    Utf8Latin1,
+    /// Every byte as a decimal number
+    Decimal,
+    /// Every byte as two hexadecimal digits
+    Hex,
+}
+
+#[derive(Clone, Debug, ValueEnum)]
+enum Extension {
+    /// pbrain procedures: `(`, `)` and `:`
+    Pbrain,
+    /// Extended Brainfuck Type I: `@`, `$`, `!`, `{`, `}`, `~`, `&`, `|` and `^`
+    Extended,
+}
+
+impl Args {
+    fn extensions(&self) -> Extensions {
+        let mut extensions = Extensions::default();
+
+        for extension in &self.extensions {
+            match extension {
+                Extension::Pbrain => extensions.procedures = true,
+                Extension::Extended => extensions.extended = true,
+            }
+        }
+
+        extensions
+    }
+
+    fn io_mode(&self) -> IoMode {
+        if self.numeric_io {
+            IoMode::Numeric
+        } else {
+            IoMode::Bytes
+        }
+    }
+
+    fn flush(&self) -> Flush {
+        match self.flush {
+            Some(FlushMode::Always) => Flush::Always,
+            Some(FlushMode::Line) => Flush::Line,
+            Some(FlushMode::Exit) => Flush::Exit,
+            None if io::stdout().is_terminal() => Flush::Line,
+            None => Flush::Exit,
+        }
+    }
+
+    fn output_format(&self) -> OutputFormat {
+        match self.output {
+            Output::Raw => OutputFormat::Raw,
+            Output::Utf8Latin1 => OutputFormat::Utf8Latin1,
+            Output::Decimal => OutputFormat::Decimal,
+            Output::Hex => OutputFormat::Hex,
+        }
+    }
+
+    /// Whether the program starts on a tape that only holds zeros.
+    fn zeroed_tape(&self) -> bool {
+        self.tape_init.is_none()
+    }
+
+    fn jit(&self) -> Jit {
+        Jit::new()
+            .set_tape_size(self.tape_size)
+            .assume_zeroed_tape(self.zeroed_tape())
+            .set_io_mode(self.io_mode())
+    }
+
+    fn execution_options(&self) -> ExecutionOptions {
+        ExecutionOptions {
+            tape_size: self.tape_size,
+            zeroed_tape: self.zeroed_tape(),
+            io_mode: self.io_mode(),
+            flush: self.flush(),
+            output_format: self.output_format(),
+        }
+    }
+
+    /// The backend chosen with `--backend` or `--tiered`, the host's JIT by default.
+    fn backend(&self) -> Box<dyn Backend> {
+        if self.tiered || self.backend.as_deref() == Some("tiered") {
+            return Box::new(Tiered::new().set_threshold(self.tier_threshold));
+        }
+
+        match &self.backend {
+            Some(name) => find_backend(name).unwrap(),
+            None => backends().remove(0),
+        }
+    }
+
+    fn pass_manager(&self) -> PassManager {
+        let manager = match &self.passes {
+            Some(passes) => passes
+                .iter()
+                .fold(PassManager::new(), |manager, &pass| manager.add_pass(pass)),
+            None if self.opt => PassManager::with_level(self.level),
+            None => PassManager::new(),
+        };
+
+        self.disabled_passes
+            .iter()
+            .fold(manager, |manager, &pass| manager.disable_pass(pass))
+            .assume_zeroed_tape(self.zeroed_tape())
+            .keep_final_tape(self.dump_tape.is_some())
+    }
 }
 
 fn main() {
     let args = Args::parse();
-    let file = File::open(args.program).expect("Could not read program");
+    check_tape_args(&args);
 
-    let parse = parse(file);
-    let program = if args.opt { optimize(parse) } else { parse };
+    match args.command {
+        Some(Command::Repl) => {
+            repl::run(&args);
+            return;
+        }
+        Some(Command::Backends) => {
+            for backend in backends() {
+                println!("{:<12} {}", backend.name(), backend.description());
+            }
+            return;
+        }
+        None => (),
+    }
 
-    if matches!(args.emit, Some(Emit::Ast)) {
-        println!("{:?}", program);
+    if let Some(dir) = &args.coverage {
+        run_coverage(&args, dir);
         return;
     }
 
+    let source = fs::read(args.program.as_ref().unwrap()).expect("Could not read program");
+
+    if let Some(dir) = &args.cache_dir {
+        run_cached(&args, dir, &source);
+        return;
+    }
+
+    if args.stats {
+        show_stats(&args, &source);
+        return;
+    }
+
+    let program = parse_program(&args, &source);
+
+    match args.emit {
+        Some(Emit::Ast) => {
+            println!("{:?}", program);
+            return;
+        }
+        Some(Emit::Json) => {
+            println!("{}", serde_json::to_string(&program).unwrap());
+            return;
+        }
+        None => (),
+    }
+
+    match args
+        .backend()
+        .compile(&program, &args.execution_options())
+        .expect("Could not compile program")
+    {
+        Artifact::Runnable(runnable) => run_on_tape(&args, &*runnable),
+        Artifact::Emitted(bytes) => io::stdout()
+            .write_all(&bytes)
+            .expect("Could not write output"),
+    }
+}
+
+fn check_tape_args(args: &Args) {
+    let fail = |msg: &str| {
+        Args::command()
+            .error(ErrorKind::ValueValidation, msg)
+            .exit()
+    };
+
+    if args
+        .tape_init
+        .as_ref()
+        .is_some_and(|init| init.0.len() > args.tape_size)
     {
-        let jit = Jit::new().set_tape_size(args.tape_size);
-        let fun = jit.compile(&program);
-        fun.run();
-        std::io::stdout().flush().unwrap();
+        fail("--tape-init holds more cells than the tape");
+    }
+
+    if args.start_at.is_some_and(|start| start >= args.tape_size) {
+        fail("--start-at is outside the tape");
+    }
+
+    // Subcommands don't run a program file on a prepared tape.
+    let tape_args = [
+        ("--tape-init", args.tape_init.is_some()),
+        ("--start-at", args.start_at.is_some()),
+        ("--dump-tape", args.dump_tape.is_some()),
+    ];
+    if let (Some(_), Some((name, _))) = (&args.command, tape_args.iter().find(|arg| arg.1)) {
+        Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                format!("{} can't be used with a subcommand", name),
+            )
+            .exit();
+    }
+}
+
+/// Runs `runnable` on a tape set up by `--tape-init` and `--start-at`, and writes the tape to
+/// the `--dump-tape` file afterwards.
+fn run_on_tape(args: &Args, runnable: &dyn Runnable) {
+    let mut tape = vec![0; args.tape_size];
+
+    if let Some(TapeInit(init)) = &args.tape_init {
+        tape[..init.len()].copy_from_slice(init);
     }
+
+    runnable.run_on(&mut tape, args.start_at.unwrap_or(0));
+
+    if let Some(path) = &args.dump_tape {
+        fs::write(path, &tape).expect("Could not write tape");
+    }
+}
+
+fn parse_program(args: &Args, source: &[u8]) -> Program {
+    args.pass_manager().run(parse_unoptimized(args, source))
+}
+
+fn parse_unoptimized(args: &Args, source: &[u8]) -> Program {
+    match &args.dialect {
+        Some(dialect) => dialect.parse(source),
+        None => args.extensions().parse(source),
+    }
+}
+
+fn show_stats(args: &Args, source: &[u8]) {
+    let program = parse_unoptimized(args, source);
+    let manager = args.pass_manager();
+    let function = args
+        .jit()
+        .compile(&manager.run(program.clone()))
+        .expect("Could not compile program");
+
+    print!(
+        "{}",
+        Stats::collect(&program, &manager).set_code_size(&function)
+    );
+}
+
+/// The cells `--tape-init` fills the start of the tape with.
+#[derive(Clone, Debug)]
+struct TapeInit(Vec<u8>);
+
+fn parse_tape_init(value: &str) -> Result<TapeInit, String> {
+    let Some(hex) = value.strip_prefix("hex:") else {
+        return fs::read(value)
+            .map(TapeInit)
+            .map_err(|e| format!("{}: {}", value, e));
+    };
+
+    if !hex.is_ascii() || hex.len() % 2 != 0 {
+        return Err("expected two hex digits per cell".to_string());
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| format!("invalid hex: {}", hex))
+        })
+        .collect::<Result<_, _>>()
+        .map(TapeInit)
+}
+
+fn parse_backend(name: &str) -> Result<String, String> {
+    match find_backend(name) {
+        Some(_) => Ok(name.to_string()),
+        None => {
+            let names: Vec<_> = backends().iter().map(|backend| backend.name()).collect();
+            Err(format!("available backends: {}", names.join(", ")))
+        }
+    }
+}
+
+fn parse_dialect(name: &str) -> Result<Dialect, String> {
+    if let Some(dialect) = Dialect::builtin(name) {
+        return Ok(dialect);
+    }
+
+    let mapping = fs::read_to_string(name).map_err(|e| format!("{}: {}", name, e))?;
+    Dialect::from_mapping(&mapping).map_err(|e| format!("{}: {}", name, e))
+}
+
+fn run_cached(args: &Args, dir: &Path, source: &[u8]) {
+    let jit = args.jit();
+    let key = jit
+        .cache_key(source)
+        .option("passes", format!("{:?}", args.pass_manager().passes()))
+        .option("extensions", format!("{:?}", args.extensions()))
+        .option("dialect", format!("{:?}", args.dialect))
+        .option("final-tape", args.dump_tape.is_some());
+    let cache = Cache::new(dir);
+
+    let fun = match cache.load(&key) {
+        Ok(Some(fun)) => fun,
+        result => {
+            if let Err(e) = result {
+                eprintln!("warning: ignoring cached code: {}", e);
+            }
+
+            let fun = jit
+                .compile(&parse_program(args, source))
+                .expect("Could not compile program");
+
+            if let Err(e) = cache.store(&key, &fun) {
+                eprintln!("warning: could not write cache: {}", e);
+            }
+
+            fun
+        }
+    };
+
+    let fun = fun
+        .set_flush(args.flush())
+        .set_output_format(args.output_format());
+    run_on_tape(args, &fun);
+}
+
+fn run_coverage(args: &Args, dir: &Path) {
+    let program = args.program.as_ref().unwrap();
+    let source = fs::read(program).expect("Could not read program");
+    let (parsed, map) = match &args.dialect {
+        Some(dialect) => dialect.parse_with_spans(&source),
+        None => args.extensions().parse_with_spans(&source[..]),
+    };
+
+    let coverage = Coverage::collect(&parsed, &map, args.tape_size);
+
+    fs::create_dir_all(dir).expect("Could not create coverage directory");
+
+    let mut lcov = File::create(dir.join("lcov.info")).expect("Could not write lcov.info");
+    coverage
+        .write_lcov(&mut lcov, &program.to_string_lossy(), &source)
+        .expect("Could not write lcov.info");
+
+    let mut listing_name = program.file_name().unwrap_or_default().to_os_string();
+    listing_name.push(".cov");
+    let mut listing = File::create(dir.join(listing_name)).expect("Could not write listing");
+    coverage
+        .write_listing(&mut listing, &source)
+        .expect("Could not write listing");
 }
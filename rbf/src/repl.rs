@@ -0,0 +1,200 @@
+use std::fs;
+use std::io::{self, BufRead, IsTerminal, Write};
+
+use librbf::Jit;
+
+use crate::{Args, parse_unoptimized};
+
+/// Cells shown on each side of the data pointer by `/tape`.
+const TAPE_WINDOW: usize = 8;
+
+const HELP: &str = "\
+Enter Brainfuck code to run it. The tape and data pointer persist between lines.
+Lines with unclosed loops or procedures continue on the next line.
+
+/tape [N]    Shows N cells around the data pointer (default 8)
+/reset       Zeroes the tape and moves the data pointer to cell 0
+/load FILE   Runs a program file on the current tape
+/help        Shows this help
+/quit        Exits";
+
+struct Repl<'a> {
+    args: &'a Args,
+    tape: Vec<u8>,
+    pointer: usize,
+}
+
+enum Nesting {
+    Closed,
+    Open,
+    Unbalanced,
+}
+
+/// Reads code from STDIN line by line and runs it until STDIN is closed or `/quit` is entered.
+pub fn run(args: &Args) {
+    let interactive = io::stdin().is_terminal();
+    let mut repl = Repl {
+        args,
+        tape: vec![0; args.tape_size],
+        pointer: 0,
+    };
+    let mut pending = Vec::new();
+
+    if interactive {
+        println!("rbf {}, enter /help for help", env!("CARGO_PKG_VERSION"));
+    }
+
+    loop {
+        if interactive {
+            print!("{}", if pending.is_empty() { "bf> " } else { "... " });
+            io::stdout().flush().unwrap();
+        }
+
+        let mut line = Vec::new();
+        if io::stdin().lock().read_until(b'\n', &mut line).unwrap() == 0 {
+            break;
+        }
+
+        if pending.is_empty()
+            && let Some(command) = line.trim_ascii().strip_prefix(b"/")
+        {
+            match repl.command(&String::from_utf8_lossy(command)) {
+                Some(()) => continue,
+                None => break,
+            }
+        }
+
+        pending.extend_from_slice(&line);
+
+        match repl.nesting(&pending) {
+            Nesting::Open => continue,
+            Nesting::Unbalanced => eprintln!("error: unmatched closing bracket"),
+            Nesting::Closed => repl.execute(&pending),
+        }
+
+        pending.clear();
+    }
+}
+
+impl Repl<'_> {
+    /// Runs a `/` command. Returns `None` when the REPL should exit.
+    fn command(&mut self, command: &str) -> Option<()> {
+        let (name, argument) = command.split_once(' ').unwrap_or((command, ""));
+        let argument = argument.trim();
+
+        match name {
+            "tape" if argument.is_empty() => self.show_tape(TAPE_WINDOW),
+            "tape" => match argument.parse() {
+                Ok(window) => self.show_tape(window),
+                Err(_) => eprintln!("error: invalid cell count `{}`", argument),
+            },
+            "reset" => {
+                self.tape.fill(0);
+                self.pointer = 0;
+            }
+            "load" => match fs::read(argument) {
+                Ok(source) => self.execute(&source),
+                Err(e) => eprintln!("error: {}: {}", argument, e),
+            },
+            "help" => println!("{}", HELP),
+            "quit" | "exit" => return None,
+            _ => eprintln!("error: unknown command `/{}`, enter /help for help", name),
+        }
+
+        Some(())
+    }
+
+    fn show_tape(&self, window: usize) {
+        let start = self.pointer.saturating_sub(window);
+        let end = (self.pointer + window + 1).min(self.tape.len());
+
+        let cells: Vec<_> = (start..end)
+            .map(|i| {
+                if i == self.pointer {
+                    format!("[{}:{}]", i, self.tape[i])
+                } else {
+                    format!("{}:{}", i, self.tape[i])
+                }
+            })
+            .collect();
+
+        println!("{}", cells.join(" "));
+    }
+
+    /// Checks whether all loops and procedures in `source` are closed.
+    fn nesting(&self, source: &[u8]) -> Nesting {
+        let translated;
+        let source = match &self.args.dialect {
+            Some(dialect) => {
+                translated = dialect.translate(source);
+                &translated[..]
+            }
+            None => source,
+        };
+        let procedures = self.args.dialect.is_none() && self.args.extensions().procedures;
+        let mut open = Vec::new();
+
+        for &c in source {
+            match c {
+                b'[' => open.push(b']'),
+                b'(' if procedures => open.push(b')'),
+                b']' | b')' if (c == b']' || procedures) && open.pop() != Some(c) => {
+                    return Nesting::Unbalanced;
+                }
+                _ => (),
+            }
+        }
+
+        if open.is_empty() {
+            Nesting::Closed
+        } else {
+            Nesting::Open
+        }
+    }
+
+    fn execute(&mut self, source: &[u8]) {
+        if let Nesting::Unbalanced | Nesting::Open = self.nesting(source) {
+            eprintln!("error: unbalanced brackets");
+            return;
+        }
+
+        let program = self
+            .args
+            .pass_manager()
+            .assume_zeroed_tape(false)
+            .run(parse_unoptimized(self.args, source));
+        let compiled = Jit::new()
+            .set_tape_size(self.tape.len())
+            .assume_zeroed_tape(false)
+            .set_io_mode(self.args.io_mode())
+            .compile(&program)
+            .map(|function| {
+                // The next lines of code are read from stdin, too.
+                function
+                    .set_flush(self.args.flush())
+                    .set_output_format(self.args.output_format())
+                    .set_line_input(true)
+            });
+
+        let function = match compiled {
+            Ok(function) => function,
+            Err(e) => {
+                eprintln!("error: {}", e);
+                return;
+            }
+        };
+
+        // The program runs on a guarded copy of the tape, so moving off it can't touch other
+        // memory.
+        let end = function.try_run_on(&mut self.tape, self.pointer);
+        io::stdout().flush().unwrap();
+
+        match end {
+            Some(pointer) => self.pointer = pointer,
+            None => {
+                eprintln!("error: the data pointer left the tape, moving it back to cell 0");
+                self.pointer = 0;
+            }
+        }
+    }
+}
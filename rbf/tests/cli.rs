@@ -1,3 +1,4 @@
+use std::ffi::OsStr;
 use std::fs;
 use std::io::Write;
 use std::path::PathBuf;
@@ -8,26 +9,41 @@ use std::time::{SystemTime, UNIX_EPOCH};
 static NEXT_PROGRAM_ID: AtomicU64 = AtomicU64::new(0);
 
 fn run_program(source: &str, input: &[u8]) -> Vec<u8> {
+    run_program_with_args(source, &[], input)
+}
+
+fn run_program_with_args(source: &str, args: &[&str], input: &[u8]) -> Vec<u8> {
     let path = write_program(source);
+    let output = run_rbf(args.iter().map(OsStr::new).chain([path.as_os_str()]), input);
+    let _ = fs::remove_file(&path);
+    output
+}
+
+fn run_rbf<I, S>(args: I, input: &[u8]) -> Vec<u8>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
     let mut child = Command::new(env!("CARGO_BIN_EXE_rbf"))
-        .arg(&path)
+        .args(args)
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
         .spawn()
         .expect("failed to run rbf");
 
-    if !input.is_empty() {
-        child
-            .stdin
-            .take()
-            .expect("failed to open stdin")
-            .write_all(input)
-            .expect("failed to write stdin");
-    }
+    // Input is written on another thread, so rbf can't block on a full stdout pipe while the
+    // input is still being written.
+    let mut stdin = child.stdin.take().expect("failed to open stdin");
+    let input = input.to_vec();
+    let writer = std::thread::spawn(move || {
+        if !input.is_empty() {
+            stdin.write_all(&input).expect("failed to write stdin");
+        }
+    });
 
     let output = child.wait_with_output().expect("failed to wait for rbf");
-    let _ = fs::remove_file(&path);
+    writer.join().unwrap();
 
     assert!(
         output.status.success(),
@@ -76,3 +92,408 @@ fn prints_bulk_constant_output() {
 fn runs_optimized_multiply_loop() {
     assert_eq!(run_program("+++++[>+++++++++++++<-]>.", b""), b"A");
 }
+
+#[test]
+fn writes_coverage_reports() {
+    let path = write_program("+[-]>[-]");
+    let dir = path.with_extension("cov.d");
+
+    let output = Command::new(env!("CARGO_BIN_EXE_rbf"))
+        .arg("--coverage")
+        .arg(&dir)
+        .arg(&path)
+        .output()
+        .expect("failed to run rbf");
+    assert!(output.status.success());
+
+    let lcov = fs::read_to_string(dir.join("lcov.info")).expect("missing lcov.info");
+    let listing_name = format!("{}.cov", path.file_name().unwrap().to_string_lossy());
+    let listing = fs::read_to_string(dir.join(listing_name)).expect("missing listing");
+
+    let _ = fs::remove_file(&path);
+    let _ = fs::remove_dir_all(&dir);
+
+    assert!(lcov.contains("BRDA:1,1,0,0\n"));
+    assert!(listing.starts_with("        1:    1:+[-]>[-]\n"));
+}
+
+#[test]
+fn runs_dialect_programs() {
+    assert_eq!(
+        run_program_with_args("eeeeeeeepaeeeeeeeecisaj", &["--dialect", "alphuck"], b""),
+        b"@"
+    );
+}
+
+#[test]
+fn rejects_extensions_with_dialects() {
+    let path = write_program("eeeeeeeepaeeeeeeeecisaj");
+    let output = Command::new(env!("CARGO_BIN_EXE_rbf"))
+        .args(["--dialect", "alphuck", "-x", "pbrain"])
+        .arg(&path)
+        .output()
+        .expect("failed to run rbf");
+    let _ = fs::remove_file(&path);
+
+    assert_eq!(output.status.code(), Some(2));
+    assert!(output.stdout.is_empty());
+}
+
+#[test]
+fn runs_pbrain_procedures() {
+    // Procedure 1 prints the next cell plus one, procedure 2 calls procedure 1 twice.
+    let source = "+(>+.<)+(-::+)>>++++++++[<++++++++>-]<<:";
+    assert_eq!(run_program_with_args(source, &["-x", "pbrain"], b""), b"AB");
+}
+
+#[test]
+fn runs_extended_type_1_instructions() {
+    // 'A' is stored, shifted right to ' ' and restored, then XORed into 'D' to give 0x05.
+    let source = "+++++++[>+++++++++<-]>++$.}.!+++^.@.";
+    assert_eq!(
+        run_program_with_args(source, &["-x", "extended"], b""),
+        b"A \x05"
+    );
+}
+
+#[test]
+fn repl_keeps_the_tape_between_lines() {
+    let input = b"++++++++[>++++++++\n<-]>\n+.\n/tape 1\n/reset\n/tape 1\n/quit\n+.";
+
+    assert_eq!(
+        run_rbf(["repl"], input),
+        b"A0:0 [1:65] 2:0\n[0:0] 1:0\n".to_vec()
+    );
+}
+
+#[test]
+fn repl_reads_piped_input_one_line_at_a_time() {
+    let input = b",.\nA\n[-]++++++++[>++++++++<-]>+.\n";
+
+    assert_eq!(run_rbf(["repl"], input), b"AA");
+}
+
+#[test]
+fn repl_formats_output() {
+    let input = b"++++++++[>++++++++<-]>+.\n+.\n";
+
+    assert_eq!(run_rbf(["--output", "hex", "repl"], input), b"41\n42\n");
+}
+
+#[test]
+fn repl_moves_the_data_pointer_back_onto_the_tape() {
+    let input = b"+>+\n<<\n/tape 1\n/quit\n";
+
+    assert_eq!(run_rbf(["repl"], input), b"[0:1] 1:1\n".to_vec());
+}
+
+#[test]
+fn runs_tiered_programs() {
+    let source = "++++++++[>++++++++<-]>+.[-]++++++++++[>++++++++++[>+<-]<-]>>[-<+>]<---.";
+
+    for threshold in ["0", "5", "1000"] {
+        assert_eq!(
+            run_program_with_args(source, &["--tiered", "--tier-threshold", threshold], b""),
+            b"Aa".to_vec()
+        );
+    }
+}
+
+#[test]
+fn runs_balanced_loops() {
+    // Both loops only read the `A` three cells to the right of the outer loop's cell.
+    let source = format!(">>>{}<<<++[>+++[>>.<<-]<-]+++[>>>.+<<<-]", "+".repeat(65));
+    let args: [&[&str]; 3] = [&["-O0"], &["-O3"], &["--tiered", "--tier-threshold", "1"]];
+
+    for args in args {
+        assert_eq!(run_program_with_args(&source, args, b""), b"AAAAAAABC");
+    }
+}
+
+#[test]
+fn reuses_cached_code() {
+    let dir = std::env::temp_dir().join(format!("rbf-cli-cache-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let args = ["--cache-dir", dir.to_str().unwrap()];
+    let source = "++++++++[>++++++++<-]>+.+.";
+
+    assert_eq!(run_program_with_args(source, &args, b""), b"AB".to_vec());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    assert_eq!(run_program_with_args(source, &args, b""), b"AB".to_vec());
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn emits_json() {
+    assert_eq!(
+        run_program_with_args("+++[>+<-]>.", &["-e", "json", "-O2"], b""),
+        br#"[{"op":"add","args":3},{"op":"mul_run","args":[[1,1]]},{"op":"move","args":1},{"op":"write"}]"#
+            .iter()
+            .chain(b"\n")
+            .copied()
+            .collect::<Vec<_>>()
+    );
+}
+
+#[test]
+fn selects_optimization_passes() {
+    let source = "+++[>+<-]>.";
+    let emit = |args: &[&str]| {
+        let args: Vec<_> = ["-e", "ast"].iter().chain(args).copied().collect();
+        String::from_utf8(run_program_with_args(source, &args, b"")).unwrap()
+    };
+
+    assert_eq!(
+        emit(&["-O0"]),
+        "[Add(3), Loop([Move(1), Add(1), Move(-1), Add(-1)]), Move(1), Write]\n"
+    );
+    assert_eq!(
+        emit(&["-O2"]),
+        "[Add(3), MulRun([(1, 1)]), Move(1), Write]\n"
+    );
+    assert_eq!(emit(&["--passes", "mul-run"]), emit(&["-O2"]));
+    assert_eq!(emit(&["-O2", "--disable-pass", "mul-run"]), emit(&["-O1"]));
+}
+
+#[test]
+fn runs_multiplication_loops_with_odd_steps() {
+    // 195 / 3 = 65, and `[--->+<]` adds one for every three it subtracts.
+    let source = format!("{}[--->+<]>.", "+".repeat(195));
+
+    assert_eq!(run_program_with_args(&source, &["-O0"], b""), b"A");
+    assert_eq!(run_program_with_args(&source, &["-O3"], b""), b"A");
+}
+
+#[test]
+fn evaluates_output_before_the_first_read() {
+    let source = "++++++++[>++++++++<-]>+.+.,.";
+
+    assert_eq!(
+        run_program_with_args(source, &["-e", "ast"], b""),
+        b"[Move(1), WriteBytes([65, 66]), Read, Write]\n"
+    );
+    assert_eq!(run_program_with_args(source, &[], b"x"), b"ABx");
+}
+
+#[test]
+fn shows_optimization_stats() {
+    let stats = run_program_with_args("++[>+<-]>[.>]", &["--stats"], b"");
+    let stats = String::from_utf8(stats).unwrap();
+
+    assert!(stats.contains("source commands: 13\n"), "{}", stats);
+    assert!(stats.contains("  became MulRun: 1\n"), "{}", stats);
+    assert!(stats.contains("    input or output: 1\n"), "{}", stats);
+    assert!(stats.contains("code size ("), "{}", stats);
+}
+
+#[test]
+fn writes_bytes_unchanged() {
+    let source = format!("{}.,.", "-".repeat(23));
+
+    for args in [&[][..], &["-O0"], &["--tiered", "--tier-threshold", "0"]] {
+        assert_eq!(
+            run_program_with_args(&source, args, b"\xff"),
+            b"\xe9\xff",
+            "{:?}",
+            args
+        );
+    }
+}
+
+#[test]
+fn flushes_output_in_every_mode() {
+    // Writes 2 * 255 * 255 ones, more than fit into the output buffer.
+    let source = ">>>+<<<++[>-[>-[>.<-]<-]<-]";
+
+    for flush in ["always", "line", "exit"] {
+        for level in ["-O0", "-O3"] {
+            let output = run_program_with_args(source, &["--flush", flush, level], b"");
+
+            assert_eq!(output.len(), 2 * 255 * 255, "{} {}", flush, level);
+            assert!(output.iter().all(|&byte| byte == 1), "{} {}", flush, level);
+        }
+    }
+}
+
+#[test]
+fn formats_output() {
+    let source = format!("{}.+.{}.", "-".repeat(23), "+".repeat(23));
+
+    for (format, expected) in [
+        ("raw", &b"\xe9\xea\x01"[..]),
+        ("utf8-latin1", "éê\u{1}".as_bytes()),
+        ("decimal", b"233 234 1\n"),
+        ("hex", b"e9 ea 01\n"),
+    ] {
+        for level in ["-O0", "-O3"] {
+            assert_eq!(
+                run_program_with_args(&source, &["--output", format, level], b""),
+                expected,
+                "{} {}",
+                format,
+                level
+            );
+        }
+    }
+}
+
+#[test]
+fn reads_and_writes_numbers() {
+    // Adds two numbers and writes both of them and their sum.
+    let source = ",>,<.>.[<+>-]<.";
+
+    for args in [
+        &["--numeric-io", "-O0"][..],
+        &["--numeric-io"],
+        &["--numeric-io", "--tiered", "--tier-threshold", "0"],
+    ] {
+        assert_eq!(
+            run_program_with_args(source, args, b"  200\n-3 "),
+            b"200\n253\n197\n",
+            "{:?}",
+            args
+        );
+    }
+}
+
+#[test]
+fn writes_folded_output_as_numbers() {
+    let source = "++++++++[>++++++++<-]>+.+.";
+
+    assert_eq!(
+        run_program_with_args(source, &["--numeric-io"], b""),
+        b"65\n66\n"
+    );
+    assert_eq!(
+        run_program_with_args(source, &["--numeric-io", "-O0"], b""),
+        b"65\n66\n"
+    );
+}
+
+#[test]
+fn reads_input_in_blocks() {
+    // Echoes the input up to the first zero byte, which the end of the input reads as.
+    let source = ",[.,]";
+    let input: Vec<u8> = (0..200_000).map(|i| (i % 255 + 1) as u8).collect();
+
+    for args in [&[][..], &["-O0"], &["--tiered", "--tier-threshold", "0"]] {
+        assert_eq!(
+            run_program_with_args(source, args, &input),
+            input,
+            "{:?}",
+            args
+        );
+    }
+}
+
+#[test]
+fn runs_on_a_prepared_tape() {
+    // Prints the cells before the start, which only hold data when the tape was filled.
+    let source = "<<<.>.>.";
+    let args = ["--tape-init", "hex:414243", "--start-at", "3"];
+
+    assert_eq!(run_program_with_args(source, &args, b""), b"ABC");
+    assert_eq!(
+        run_program_with_args(source, &["--start-at", "3"], b""),
+        b"\0\0\0"
+    );
+
+    let init = write_program("xyz");
+    let args = [
+        "--tape-init",
+        init.to_str().unwrap(),
+        "--start-at",
+        "3",
+        "-O0",
+    ];
+    assert_eq!(run_program_with_args(source, &args, b""), b"xyz");
+    let _ = fs::remove_file(&init);
+}
+
+#[test]
+fn dumps_the_tape() {
+    let dump = write_program("").with_extension("tape");
+    let args = [
+        "-t",
+        "8",
+        "--tape-init",
+        "hex:0102",
+        "--start-at",
+        "1",
+        "--dump-tape",
+        dump.to_str().unwrap(),
+    ];
+
+    assert_eq!(run_program_with_args("[->++<]<+", &args, b""), b"");
+    let tape = fs::read(&dump).expect("missing tape dump");
+    let _ = fs::remove_file(&dump);
+
+    assert_eq!(tape, [2, 0, 4, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn rejects_tape_args_that_would_be_ignored() {
+    let path = write_program("+");
+    let program = path.to_str().unwrap();
+    let cases: [&[&str]; 4] = [
+        &["--start-at", "1", "repl"],
+        &["--dump-tape", "tape", "backends"],
+        &["--tape-init", "hex:01", "-e", "ast", program],
+        &["--start-at", "1", "-e", "json", program],
+    ];
+
+    for args in cases {
+        let output = Command::new(env!("CARGO_BIN_EXE_rbf"))
+            .args(args)
+            .stdin(Stdio::null())
+            .output()
+            .expect("failed to run rbf");
+
+        assert_eq!(output.status.code(), Some(2), "{:?}", args);
+        assert!(output.stdout.is_empty(), "{:?}", args);
+    }
+
+    let _ = fs::remove_file(&path);
+}
+
+#[test]
+fn runs_every_backend() {
+    let list = String::from_utf8(run_rbf(["backends"], b"")).unwrap();
+    let names: Vec<_> = list
+        .lines()
+        .map(|line| line.split(' ').next().unwrap())
+        .collect();
+    assert_eq!(names.len(), 2, "{}", list);
+
+    for name in names {
+        let args = [
+            "--backend",
+            name,
+            "--tape-init",
+            "hex:40",
+            "--start-at",
+            "1",
+        ];
+        assert_eq!(run_program_with_args("<+.", &args, b""), b"A", "{}", name);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn stops_programs_that_leave_the_tape() {
+    use std::os::unix::process::ExitStatusExt;
+
+    let path = write_program(&format!(",{}+.", "<".repeat(32)));
+    let output = Command::new(env!("CARGO_BIN_EXE_rbf"))
+        .arg(&path)
+        .stdin(Stdio::null())
+        .output()
+        .expect("failed to run rbf");
+    let _ = fs::remove_file(&path);
+
+    // SIGSEGV
+    assert_eq!(output.status.signal(), Some(11));
+    assert!(output.stdout.is_empty());
+}